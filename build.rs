@@ -0,0 +1,27 @@
+//! Builds the proc_service shim, see src/shim.c and src/dl.rs.
+
+use std::path::{Path, PathBuf};
+
+/// Compiles the C file `source` into the shared library `out`.
+fn build_shared(source: &Path, out: &Path) {
+    // cc only builds static libraries, so just borrow its compiler detection.
+    let compiler = cc::Build::new().pic(true).get_compiler();
+    let status = compiler.to_command()
+        .args(["-shared", "-o"])
+        .arg(out)
+        .arg(source)
+        .status()
+        .expect("could not run the C compiler");
+    assert!(status.success(), "building {} failed", source.display());
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/shim.c");
+    let out_dir = PathBuf::from(std::env::var_os("OUT_DIR").unwrap());
+    build_shared(Path::new("src/shim.c"), &out_dir.join("libthread_db_shim.so"));
+
+    // A library requiring a callback that we don't provide, for the tests in src/dl.rs.
+    let source = out_dir.join("unknown_callback.c");
+    std::fs::write(&source, "int ps_unknown(void);\nint call_unknown(void) { return ps_unknown(); }\n").unwrap();
+    build_shared(&source, &out_dir.join("libunknown_callback.so"));
+}
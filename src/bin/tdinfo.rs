@@ -0,0 +1,176 @@
+//! Lists the threads of a running process or a core, like gdb's `info threads` and
+//! `thread apply all bt`.
+
+use std::path::PathBuf;
+use std::process::exit;
+
+use serde::Serialize;
+
+use libthread_db::{Frame, Library, Process, Thread, ThreadInfo};
+
+const USAGE: &str = "usage: tdinfo [--json] [--backtrace] <pid|core>
+
+Lists the threads of a running process, or of the process dumped in a core file.
+
+options:
+    --json          print JSON instead of a table
+    -b, --backtrace include a backtrace for each thread
+    -h, --help      print this help";
+
+/// The process to inspect.
+enum Target {
+    Pid(i32),
+    Core(PathBuf),
+}
+
+struct Options {
+    target: Target,
+    json: bool,
+    backtrace: bool,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut target = None;
+    let mut json = false;
+    let mut backtrace = false;
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-b" | "--backtrace" => backtrace = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if target.is_some() => return Err("only one pid or core can be given".to_string()),
+            // Anything that isn't a pid is a path, so name cores like `./1234` to read them.
+            _ => target = Some(match arg.parse() {
+                Ok(pid) => Target::Pid(pid),
+                Err(_) => Target::Core(PathBuf::from(arg)),
+            }),
+        }
+    }
+    let target = target.ok_or("no pid or core given")?;
+    Ok(Options { target, json, backtrace })
+}
+
+/// Everything printed about a process.
+#[derive(Serialize)]
+struct Report {
+    pid: i32,
+    threads: Vec<ThreadReport>,
+}
+
+/// Everything printed about a thread.
+#[derive(Serialize)]
+struct ThreadReport {
+    #[serde(flatten)]
+    info: ThreadInfo,
+    start_symbol: Option<Symbol>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backtrace: Option<Vec<FrameReport>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backtrace_error: Option<String>,
+}
+
+/// A stack frame with the symbol of its program counter.
+#[derive(Serialize)]
+struct FrameReport {
+    #[serde(flatten)]
+    frame: Frame,
+    symbol: Option<Symbol>,
+}
+
+/// The symbol containing an address, see `Process::symbolize()`.
+#[derive(Serialize)]
+struct Symbol {
+    module: String,
+    name: String,
+    offset: usize,
+}
+
+impl Symbol {
+    fn lookup(process: &Process, addr: usize) -> Option<Symbol> {
+        process.symbolize(addr).map(|(module, name, offset)| Symbol { module: module.to_string(), name: name.to_string(), offset })
+    }
+}
+
+fn report(process: &Process, thread: &Thread, backtrace: bool) -> Result<ThreadReport, libthread_db::Error> {
+    let info = thread.thread_info()?;
+    let start_symbol = Symbol::lookup(process, info.start_func);
+    let (backtrace, backtrace_error) = match backtrace.then(|| thread.backtrace()) {
+        Some(Ok(frames)) => {
+            let frames = frames.into_iter().map(|frame| FrameReport { symbol: Symbol::lookup(process, frame.pc), frame }).collect();
+            (Some(frames), None)
+        }
+        Some(Err(e)) => (None, Some(e.to_string())),
+        None => (None, None),
+    };
+    Ok(ThreadReport { info, start_symbol, backtrace, backtrace_error })
+}
+
+/// Formats an address as `symbol+offset`, or in hex if there's no symbol.
+fn format_addr(addr: usize, symbol: &Option<Symbol>) -> String {
+    match symbol {
+        Some(symbol) => format!("{}+{:#x}", symbol.name, symbol.offset),
+        None => format!("{:#x}", addr),
+    }
+}
+
+fn print_table(report: &Report) {
+    println!("{:>8}  {:>14}  {:<16} {:<12} {:<36} STACK", "LWP", "PTHREAD_T", "NAME", "STATE", "START");
+    for thread in &report.threads {
+        let info = &thread.info;
+        let state = info.task.as_ref().map_or_else(|| "?".to_string(), |task| format!("{:?}", task.state));
+        let start = if info.start_func == 0 { "-".to_string() } else { format_addr(info.start_func, &thread.start_symbol) };
+        let stack = if info.stack.start == info.stack.end {
+            "-".to_string()
+        } else {
+            format!("{:#x}-{:#x}", info.stack.start, info.stack.end)
+        };
+        println!("{:>8}  {:>#14x}  {:<16} {:<12} {:<36} {}",
+                 info.lwp, info.tid, info.name.as_deref().unwrap_or("?"), state, start, stack);
+        for (i, frame) in thread.backtrace.iter().flatten().enumerate() {
+            println!("    #{:<3} {:#018x} {} ({})", i, frame.frame.pc, format_addr(frame.frame.pc, &frame.symbol),
+                     frame.frame.module.as_deref().unwrap_or("?"));
+        }
+        if let Some(e) = &thread.backtrace_error {
+            println!("    backtrace failed: {}", e);
+        }
+    }
+}
+
+fn run(options: &Options) -> Result<(), libthread_db::Error> {
+    let process = match &options.target {
+        Target::Pid(pid) => Library::for_process(*pid)?.attach_owned(*pid)?,
+        // Cores of local processes match the system's libthread_db.
+        Target::Core(path) => Library::try_new()?.attach_core(path)?,
+    };
+    let mut threads = Vec::new();
+    for thread in process.threads()? {
+        threads.push(report(&process, &thread, options.backtrace)?);
+    }
+    // libthread_db lists the newest thread first.
+    threads.sort_by_key(|thread| thread.info.lwp);
+    let report = Report { pid: process.pid(), threads };
+    if options.json {
+        println!("{}", serde_json::to_string(&report).expect("serializing the report failed"));
+    } else {
+        print_table(&report);
+    }
+    Ok(())
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("tdinfo: {}\n\n{}", e, USAGE);
+            exit(2);
+        }
+    };
+    if let Err(e) = run(&options) {
+        eprintln!("tdinfo: {}", e);
+        exit(1);
+    }
+}
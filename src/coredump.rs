@@ -0,0 +1,289 @@
+//! Core files of crashed or dumped processes, as written by the kernel.
+//!
+//! The memory is read from the PT_LOAD segments of the core. Segments the kernel didn't dump, like
+//! read-only mappings of files with the default `coredump_filter` (see core(5)), are read from the
+//! mapped files listed in the NT_FILE note instead. Threads and their registers come from the
+//! NT_PRSTATUS notes.
+//!
+//! Only x86-64 cores are supported, see `<sys/procfs.h>` for the layout of the notes.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use goblin::elf64::header::{Header, EM_X86_64, ET_CORE, SIZEOF_EHDR};
+use goblin::elf64::program_header::{ProgramHeader, PT_LOAD, PT_NOTE, SIZEOF_PHDR};
+
+use crate::procfs::Mapping;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_PRFPREG: u32 = 2;
+const NT_PRPSINFO: u32 = 3;
+const NT_FILE: u32 = 0x4649_4c45;
+
+/// Offsets into `struct elf_prstatus`.
+const PRSTATUS_PID: usize = 32;
+const PRSTATUS_REG: usize = 112;
+/// Offsets into `struct elf_prpsinfo`.
+const PRPSINFO_PID: usize = 24;
+const PRPSINFO_FNAME: usize = 40;
+const PRPSINFO_FNAME_LEN: usize = 16;
+
+/// A thread of the dumped process with its registers.
+pub struct CoreThread {
+    pub lwp: libc::pid_t,
+    pub regs: libc::user_regs_struct,
+    /// `None` if the core doesn't include them.
+    pub fpregs: Option<libc::user_fpregs_struct>,
+}
+
+/// A PT_LOAD segment, i.e. a mapping of the process.
+struct Segment {
+    vaddr: usize,
+    memsz: usize,
+    /// Offset of the dumped contents in the core.
+    offset: u64,
+    /// Size of the dumped contents, 0 if the mapping wasn't dumped and only the first page for
+    /// some file mappings.
+    filesz: usize,
+}
+
+/// A core file opened for reading.
+pub struct CoreFile {
+    file: File,
+    path: PathBuf,
+    /// Process ID of the dumped process.
+    pub pid: libc::pid_t,
+    /// Name of the main thread, as in `/proc/<pid>/comm`.
+    pub fname: String,
+    /// In the order of the core, which starts with the thread that caused the dump.
+    pub threads: Vec<CoreThread>,
+    segments: Vec<Segment>,
+    /// Files mapped into the process, from the NT_FILE note.
+    pub mappings: Vec<Mapping>,
+    /// Mapped files opened so far, for reading segments that weren't dumped.
+    mapped_files: RefCell<HashMap<String, File>>,
+}
+
+impl CoreFile {
+    /// Opens and parses the core at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<CoreFile> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let mut ehdr = [0u8; SIZEOF_EHDR];
+        file.read_exact_at(&mut ehdr, 0)?;
+        let header = Header::parse(&ehdr).map_err(|e| invalid(&e.to_string()))?;
+        if header.e_type != ET_CORE {
+            return Err(invalid("not a core file"));
+        }
+        if header.e_machine != EM_X86_64 {
+            return Err(invalid("only x86-64 cores are supported"));
+        }
+        let mut phdrs = vec![0u8; header.e_phnum as usize * SIZEOF_PHDR];
+        file.read_exact_at(&mut phdrs, header.e_phoff)?;
+        let phdrs = ProgramHeader::parse(&phdrs, 0, header.e_phnum as usize, goblin::container::Endian::Little)
+            .map_err(|e| invalid(&e.to_string()))?;
+
+        let mut core = CoreFile {
+            file,
+            path: path.to_path_buf(),
+            pid: 0,
+            fname: String::new(),
+            threads: Vec::new(),
+            segments: Vec::new(),
+            mappings: Vec::new(),
+            mapped_files: RefCell::new(HashMap::new()),
+        };
+        for ph in &phdrs {
+            match ph.p_type {
+                PT_LOAD => core.segments.push(Segment {
+                    vaddr: ph.p_vaddr as usize,
+                    memsz: ph.p_memsz as usize,
+                    offset: ph.p_offset,
+                    filesz: ph.p_filesz as usize,
+                }),
+                PT_NOTE => {
+                    let mut notes = vec![0u8; ph.p_filesz as usize];
+                    core.file.read_exact_at(&mut notes, ph.p_offset)?;
+                    core.parse_notes(&notes)?;
+                }
+                _ => (),
+            }
+        }
+        if core.threads.is_empty() {
+            return Err(invalid("core has no NT_PRSTATUS note"));
+        }
+        // Cores without NT_PRPSINFO still name the thread that caused the dump first.
+        if core.pid == 0 {
+            core.pid = core.threads[0].lwp;
+        }
+        Ok(core)
+    }
+
+    /// Parses the notes of a PT_NOTE segment.
+    fn parse_notes(&mut self, mut notes: &[u8]) -> io::Result<()> {
+        while notes.len() >= 12 {
+            let namesz = u32_at(notes, 0) as usize;
+            let descsz = u32_at(notes, 4) as usize;
+            let n_type = u32_at(notes, 8);
+            // Names and descriptors are padded to 4 bytes.
+            let desc_start = 12 + align4(namesz);
+            let next = desc_start + align4(descsz);
+            if next > notes.len() {
+                return Err(invalid("truncated note"));
+            }
+            let name = &notes[12..12 + namesz];
+            let desc = &notes[desc_start..desc_start + descsz];
+            if name == b"CORE\0" {
+                self.parse_note(n_type, desc)?;
+            }
+            notes = &notes[next..];
+        }
+        Ok(())
+    }
+
+    fn parse_note(&mut self, n_type: u32, desc: &[u8]) -> io::Result<()> {
+        match n_type {
+            NT_PRSTATUS => {
+                if desc.len() < PRSTATUS_REG + std::mem::size_of::<libc::user_regs_struct>() {
+                    return Err(invalid("truncated NT_PRSTATUS note"));
+                }
+                self.threads.push(CoreThread {
+                    lwp: u32_at(desc, PRSTATUS_PID) as libc::pid_t,
+                    regs: unsafe { read_struct(&desc[PRSTATUS_REG..]) },
+                    fpregs: None,
+                });
+            }
+            // Follows the NT_PRSTATUS note of its thread.
+            NT_PRFPREG if desc.len() >= std::mem::size_of::<libc::user_fpregs_struct>() => {
+                if let Some(thread) = self.threads.last_mut() {
+                    thread.fpregs = Some(unsafe { read_struct(desc) });
+                }
+            }
+            NT_PRPSINFO => {
+                if desc.len() < PRPSINFO_FNAME + PRPSINFO_FNAME_LEN {
+                    return Err(invalid("truncated NT_PRPSINFO note"));
+                }
+                self.pid = u32_at(desc, PRPSINFO_PID) as libc::pid_t;
+                let fname = &desc[PRPSINFO_FNAME..PRPSINFO_FNAME + PRPSINFO_FNAME_LEN];
+                let len = fname.iter().position(|&c| c == 0).unwrap_or(fname.len());
+                self.fname = String::from_utf8_lossy(&fname[..len]).into_owned();
+            }
+            NT_FILE => self.mappings = parse_file_note(desc).ok_or_else(|| invalid("malformed NT_FILE note"))?,
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Returns the thread `lwp`, if it's in the core.
+    pub fn thread(&self, lwp: libc::pid_t) -> Option<&CoreThread> {
+        self.threads.iter().find(|thread| thread.lwp == lwp)
+    }
+
+    /// Reads `buf.len()` bytes at `addr` from the memory of the process. Fails with EFAULT if
+    /// any of it wasn't mapped or wasn't dumped and its file is gone.
+    pub fn read(&self, addr: usize, buf: &mut [u8]) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let current = addr.checked_add(done).ok_or_else(fault)?;
+            done += self.read_some(current, &mut buf[done..]).inspect_err(|e| {
+                log::debug!(target: "libthread_db::proc_service", path:% = self.path.display(), address = current; "reading core failed: {}", e);
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Reads at most `buf.len()` bytes at `addr` from a single segment, returns how many.
+    fn read_some(&self, addr: usize, buf: &mut [u8]) -> io::Result<usize> {
+        let segment = self.segments.iter()
+            .find(|segment| segment.vaddr <= addr && addr - segment.vaddr < segment.memsz)
+            .ok_or_else(fault)?;
+        let offset = addr - segment.vaddr;
+        if offset < segment.filesz {
+            let len = buf.len().min(segment.filesz - offset);
+            self.file.read_exact_at(&mut buf[..len], segment.offset + offset as u64)?;
+            return Ok(len);
+        }
+        // Not dumped, so it must come from a file that is still around.
+        let mapping = self.mappings.iter().find(|mapping| mapping.contains(addr)).ok_or_else(fault)?;
+        let len = buf.len().min(mapping.end - addr).min(segment.memsz - offset);
+        let mut files = self.mapped_files.borrow_mut();
+        if !files.contains_key(&mapping.name) {
+            files.insert(mapping.name.clone(), File::open(&mapping.name)?);
+        }
+        files[&mapping.name].read_exact_at(&mut buf[..len], (mapping.offset + addr - mapping.start) as u64)
+            .map_err(|e| match e.kind() {
+                // Like accessing a mapping beyond the end of its file.
+                io::ErrorKind::UnexpectedEof => fault(),
+                _ => e,
+            })?;
+        Ok(len)
+    }
+}
+
+/// Parses the NT_FILE note: the number of mappings and the page size, the start, end and page
+/// offset of each mapping and then their NUL-terminated file names.
+fn parse_file_note(desc: &[u8]) -> Option<Vec<Mapping>> {
+    let word = |index: usize| -> Option<usize> {
+        let bytes = desc.get(index * 8..index * 8 + 8)?;
+        Some(u64::from_ne_bytes(bytes.try_into().ok()?) as usize)
+    };
+    let count = word(0)?;
+    let page_size = word(1)?;
+    let mut names = desc.get(16usize.checked_add(count.checked_mul(24)?)?..)?.split(|&c| c == 0);
+    (0..count).map(|i| {
+        Some(Mapping {
+            start: word(2 + i * 3)?,
+            end: word(3 + i * 3)?,
+            offset: word(4 + i * 3)?.checked_mul(page_size)?,
+            name: String::from_utf8_lossy(names.next()?).into_owned(),
+        })
+    }).collect()
+}
+
+/// Reads a struct from the start of `bytes`, which must be large enough.
+unsafe fn read_struct<T>(bytes: &[u8]) -> T {
+    assert!(bytes.len() >= std::mem::size_of::<T>());
+    std::ptr::read_unaligned(bytes.as_ptr() as *const T)
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn align4(size: usize) -> usize {
+    (size + 3) & !3
+}
+
+fn fault() -> io::Error {
+    io::Error::from_raw_os_error(libc::EFAULT)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_file_note_works() {
+        let mut desc = Vec::new();
+        for word in &[2u64, 4096, 0x1000, 0x3000, 0, 0x5000, 0x6000, 2] {
+            desc.extend_from_slice(&word.to_ne_bytes());
+        }
+        desc.extend_from_slice(b"/bin/true\0/lib/libc.so.6\0");
+        assert_eq!(parse_file_note(&desc).unwrap(), vec![
+            Mapping { start: 0x1000, end: 0x3000, offset: 0, name: "/bin/true".to_string() },
+            Mapping { start: 0x5000, end: 0x6000, offset: 0x2000, name: "/lib/libc.so.6".to_string() },
+        ]);
+
+        // Missing names.
+        assert!(parse_file_note(&desc[..64]).is_none());
+    }
+}
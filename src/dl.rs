@@ -0,0 +1,259 @@
+//! Loading libthread_db with dlopen(3) or dlmopen(3).
+//!
+//! libthread_db doesn't link against the proc_service callbacks it uses, it expects the debugger
+//! to provide them. Resolving them through the global symbol scope would require the debugger to
+//! export them (e.g. with `-Wl,--export-dynamic`), and doesn't work at all for libraries in a new
+//! link namespace. Instead, we load libthread_db with lazy binding and point its PLT slots for the
+//! callbacks at our functions directly, before calling into the library.
+//!
+//! Copies of libthread_db that bind immediately (linked with `-z now`, or with `LD_BIND_NOW` set)
+//! fail to load without the callbacks. For those, we first load a small shim library exporting
+//! them (see src/shim.c) into the library's namespace.
+
+use std::ffi::{CStr, CString};
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::proc_service;
+
+/// Link namespace a library is loaded into.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Namespace {
+    /// The debugger's own namespace, shared with all other dlopen()ed libraries.
+    Base,
+    /// A new namespace containing only this library and its dependencies.
+    New,
+}
+
+/// Part of glibc's `struct link_map` that is documented in <link.h>.
+#[repr(C)]
+struct LinkMap {
+    /// Difference between the addresses in the ELF file and in memory.
+    l_addr: usize,
+    /// Absolute file name the object was found in.
+    l_name: *const libc::c_char,
+    l_ld: *mut libc::c_void,
+    l_next: *mut LinkMap,
+    l_prev: *mut LinkMap,
+}
+
+/// The shim exporting the proc_service callbacks, built by build.rs.
+static SHIM: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/libthread_db_shim.so"));
+
+/// The shim loaded into the base namespace, which stays loaded because the dynamic linker may
+/// have bound any library loaded since to it.
+static BASE_SHIM: OnceLock<Result<Handle, String>> = OnceLock::new();
+
+/// A loaded library, closed on drop.
+pub struct Handle {
+    handle: *mut libc::c_void,
+    /// The shim the library's callbacks resolved to, if loaded into a new namespace for it.
+    shim: Option<Box<Handle>>,
+}
+
+// The dl* functions are thread-safe.
+unsafe impl Send for Handle {}
+unsafe impl Sync for Handle {}
+
+/// Returns the message of the last dl* error.
+fn dl_error() -> String {
+    unsafe {
+        let err = libc::dlerror();
+        if err.is_null() {
+            "unknown error".to_string()
+        } else {
+            CStr::from_ptr(err).to_string_lossy().into_owned()
+        }
+    }
+}
+
+impl Handle {
+    /// Loads the library at `path` into the given namespace.
+    pub fn open(path: &Path, namespace: Namespace) -> Result<Handle, String> {
+        let c_path = CString::new(path.as_os_str().as_bytes()).map_err(|e| e.to_string())?;
+        let lmid = match namespace {
+            Namespace::Base => libc::LM_ID_BASE,
+            Namespace::New => libc::LM_ID_NEWLM,
+        };
+        // Lazy binding allows loading the library although the callbacks can't be resolved. They
+        // are bound before any libthread_db function is called.
+        let handle = match Handle::load(&c_path, lmid, libc::RTLD_LAZY | libc::RTLD_LOCAL) {
+            Ok(handle) => handle,
+            // Happens if the library is linked with -z now or LD_BIND_NOW is set.
+            Err(err) if err.contains("undefined symbol: ps_") => {
+                log::debug!(target: "libthread_db::thread_db", path:% = path.display(); "{}, loading the proc_service shim", err);
+                Handle::open_with_shim(&c_path, namespace)
+                    .map_err(|e| format!("{} (resolving it through the proc_service shim failed: {})", err, e))?
+            },
+            Err(err) => return Err(err),
+        };
+        handle.bind_callbacks()?;
+        Ok(handle)
+    }
+
+    /// Loads the library at `path` into the namespace `lmid` with dlmopen(3).
+    fn load(path: &CStr, lmid: libc::Lmid_t, flags: libc::c_int) -> Result<Handle, String> {
+        let handle = unsafe {
+            // dlmopen() doesn't accept RTLD_GLOBAL, even for the base namespace.
+            if lmid == libc::LM_ID_BASE {
+                libc::dlopen(path.as_ptr(), flags)
+            } else {
+                libc::dlmopen(lmid, path.as_ptr(), flags)
+            }
+        };
+        if handle.is_null() {
+            Err(dl_error())
+        } else {
+            Ok(Handle { handle, shim: None })
+        }
+    }
+
+    /// Loads the library at `path` into the given namespace after the shim, so that its callbacks
+    /// resolve to the shim.
+    fn open_with_shim(path: &CStr, namespace: Namespace) -> Result<Handle, String> {
+        match namespace {
+            Namespace::Base => {
+                // Symbols of libraries loaded with RTLD_GLOBAL are visible to all libraries
+                // loaded later.
+                BASE_SHIM.get_or_init(|| Handle::load_shim(libc::LM_ID_BASE, libc::RTLD_GLOBAL)).as_ref().map_err(|e| e.clone())?;
+                Handle::load(path, libc::LM_ID_BASE, libc::RTLD_LAZY | libc::RTLD_LOCAL)
+            },
+            Namespace::New => {
+                // The first library loaded into a namespace provides its global scope, like the
+                // executable does for the base namespace.
+                let shim = Handle::load_shim(libc::LM_ID_NEWLM, libc::RTLD_LOCAL)?;
+                let mut lmid: libc::Lmid_t = 0;
+                if unsafe { libc::dlinfo(shim.handle, libc::RTLD_DI_LMID, &mut lmid as *mut _ as *mut libc::c_void) } != 0 {
+                    return Err(dl_error());
+                }
+                let mut handle = Handle::load(path, lmid, libc::RTLD_LAZY | libc::RTLD_LOCAL)?;
+                handle.shim = Some(Box::new(shim));
+                Ok(handle)
+            },
+        }
+    }
+
+    /// Loads a copy of the shim into the namespace `lmid` and points its callbacks at ours.
+    fn load_shim(lmid: libc::Lmid_t, flags: libc::c_int) -> Result<Handle, String> {
+        // Load the shim from memory so that it doesn't have to be installed next to the binary.
+        let fd = unsafe { libc::memfd_create(b"libthread_db_shim.so\0".as_ptr() as *const libc::c_char, libc::MFD_CLOEXEC) };
+        if fd == -1 {
+            return Err(format!("memfd_create failed: {}", std::io::Error::last_os_error()));
+        }
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(SHIM).map_err(|e| format!("writing the shim failed: {}", e))?;
+        let path = CString::new(format!("/proc/self/fd/{}", file.as_raw_fd())).unwrap();
+        let shim = Handle::load(&path, lmid, libc::RTLD_NOW | flags)?;
+        for (callback, function) in proc_service::callbacks() {
+            let implementation = shim.symbol(&format!("{}_impl", callback))?;
+            unsafe { *(implementation as *mut usize) = function; }
+        }
+        Ok(shim)
+    }
+
+    /// Returns the shim the library's callbacks may be bound to.
+    fn shim(&self) -> Option<&Handle> {
+        self.shim.as_deref().or_else(|| BASE_SHIM.get().and_then(|shim| shim.as_ref().ok()))
+    }
+
+    /// Returns the address of the given symbol.
+    pub fn symbol(&self, name: &str) -> Result<*mut libc::c_void, String> {
+        let c_name = CString::new(name).map_err(|e| e.to_string())?;
+        let sym = unsafe { libc::dlsym(self.handle, c_name.as_ptr()) };
+        if sym.is_null() {
+            Err(dl_error())
+        } else {
+            Ok(sym)
+        }
+    }
+
+    /// Points the library's PLT slots for the proc_service callbacks at our implementations.
+    ///
+    /// Slots that the dynamic linker already bound to our implementations or to the shim are left
+    /// alone; they may be read-only after relocation (RELRO). Fails if the library requires a
+    /// callback that we don't provide or that isn't called through the PLT, or if a read-only
+    /// slot is bound to another definition.
+    fn bind_callbacks(&self) -> Result<(), String> {
+        let mut map: *mut LinkMap = std::ptr::null_mut();
+        let (base, filename) = unsafe {
+            if libc::dlinfo(self.handle, libc::RTLD_DI_LINKMAP, &mut map as *mut _ as *mut libc::c_void) != 0 {
+                return Err(dl_error());
+            }
+            ((*map).l_addr, CStr::from_ptr((*map).l_name).to_string_lossy().into_owned())
+        };
+
+        let mut buf = Vec::new();
+        File::open(&filename)
+            .and_then(|mut f| f.read_to_end(&mut buf))
+            .map_err(|e| format!("{}: {}", filename, e))?;
+        let binary = goblin::elf::Elf::parse(&buf).map_err(|e| format!("{}: {}", filename, e))?;
+
+        let relro = binary.program_headers.iter()
+            .find(|ph| ph.p_type == goblin::elf::program_header::PT_GNU_RELRO)
+            .map(|ph| base + ph.p_vaddr as usize..base + (ph.p_vaddr + ph.p_memsz) as usize);
+        let callbacks = proc_service::callbacks();
+        let mut bound = Vec::new();
+        for reloc in binary.pltrelocs.iter() {
+            let name = binary.dynsyms.get(reloc.r_sym)
+                .and_then(|sym| binary.dynstrtab.get_unsafe(sym.st_name));
+            if let Some(&(callback, function)) = callbacks.iter().find(|(callback, _)| Some(*callback) == name) {
+                let slot = (base + reloc.r_offset as usize) as *mut usize;
+                let current = unsafe { *slot };
+                let shim_function = self.shim().and_then(|shim| shim.symbol(callback).ok()).map(|f| f as usize);
+                if current != function && Some(current) != shim_function {
+                    // The GOT entries for lazily bound functions are writable, unlike those
+                    // protected by RELRO after immediate binding.
+                    if relro.as_ref().is_some_and(|relro| relro.contains(&(slot as usize))) {
+                        return Err(format!("{}: {} is bound to another definition in read-only memory", filename, callback));
+                    }
+                    unsafe { *slot = function; }
+                }
+                bound.push(callback);
+            }
+        }
+
+        // Self-check: every callback the library needs must have been bound above. Weak imports
+        // (like ps_get_thread_area) are optional and checked for NULL by libthread_db.
+        for sym in binary.dynsyms.iter() {
+            let name = binary.dynstrtab.get_unsafe(sym.st_name).unwrap_or("");
+            if name.starts_with("ps_")
+                && sym.st_shndx == goblin::elf::section_header::SHN_UNDEF as usize
+                && sym.st_bind() != goblin::elf::sym::STB_WEAK
+                && !bound.contains(&name)
+            {
+                return Err(format!("{}: can't provide proc_service callback {}", filename, name));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe {
+            if libc::dlclose(self.handle) != 0 {
+                log::warn!(target: "libthread_db::thread_db", "dlclose failed: {}", dl_error());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Refuses libraries that require a callback we don't provide.
+    #[test]
+    fn unknown_callback_fails() {
+        let path = Path::new(concat!(env!("OUT_DIR"), "/libunknown_callback.so"));
+        match Handle::open(path, Namespace::New) {
+            Err(e) => assert!(e.contains("can't provide proc_service callback ps_unknown"), "unexpected error: {}", e),
+            Ok(_) => panic!("loading a library with an unknown callback succeeded"),
+        }
+    }
+}
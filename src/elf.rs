@@ -0,0 +1,152 @@
+//! ELF files of the executable and libraries mapped into the target process.
+//!
+//! Each file is read and parsed once per process, for its symbols as well as for the call frame
+//! information used by the unwinder.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use goblin::elf::sym::{STT_FUNC, STT_GNU_IFUNC, STT_OBJECT};
+
+/// A symbol defined in an ELF file.
+pub struct ElfSymbol {
+    pub name: String,
+    pub value: usize,
+    pub size: usize,
+    /// Whether the symbol is a function or object, i.e. useful for symbolizing addresses.
+    pub indexed: bool,
+}
+
+/// Contents of a section, with its address in the ELF file.
+pub struct Section {
+    pub addr: u64,
+    pub data: Vec<u8>,
+}
+
+/// The parts of an ELF file that are needed to inspect the process.
+#[derive(Default)]
+pub struct ElfFile {
+    /// Address the start of the file is loaded at before relocation, i.e. 0 for libraries and
+    /// position-independent executables.
+    pub base: usize,
+    /// Symbols defined in the file.
+    pub symbols: Vec<ElfSymbol>,
+    pub eh_frame: Option<Section>,
+    pub debug_frame: Option<Section>,
+    pub text_addr: u64,
+}
+
+impl ElfFile {
+    /// Reads the file at `path`. Files that can't be read are empty.
+    fn read(path: &str) -> Result<ElfFile, Box<dyn std::error::Error>> {
+        let mut file = ElfFile::default();
+        log::debug!(target: "libthread_db::symbols", path = path; "reading library {}", path);
+
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!(target: "libthread_db::symbols", path = path; "couldn't read {}: {}", path, e);
+                return Ok(file);
+            }
+        };
+
+        let binary = goblin::elf::Elf::parse(&data)?;
+        file.base = binary.program_headers.iter()
+            .find(|ph| ph.p_type == goblin::elf::program_header::PT_LOAD)
+            .map_or(0, |ph| (ph.p_vaddr - ph.p_offset) as usize);
+        // Stripped libraries (like libc on most distributions) only have the dynamic symbol table,
+        // which still contains the symbols libthread_db needs.
+        let tables = [(&binary.syms, &binary.strtab), (&binary.dynsyms, &binary.dynstrtab)];
+        for (syms, strtab) in tables.iter() {
+            for sym in syms.iter() {
+                // Undefined symbols are resolved to another library.
+                if sym.st_shndx == goblin::elf::section_header::SHN_UNDEF as usize {
+                    continue;
+                }
+                if let Some(name) = strtab.get_unsafe(sym.st_name).filter(|name| !name.is_empty()) {
+                    file.symbols.push(ElfSymbol {
+                        name: name.to_string(),
+                        value: sym.st_value as usize,
+                        size: sym.st_size as usize,
+                        indexed: [STT_FUNC, STT_GNU_IFUNC, STT_OBJECT].contains(&sym.st_type()),
+                    });
+                }
+            }
+        }
+        // Only keep the sections the unwinder needs, not the whole file.
+        for sh in &binary.section_headers {
+            let (offset, size) = (sh.sh_offset as usize, sh.sh_size as usize);
+            if sh.sh_type == goblin::elf::section_header::SHT_NOBITS || offset + size > data.len() {
+                continue;
+            }
+            let section = || Some(Section { addr: sh.sh_addr, data: data[offset..offset + size].to_vec() });
+            match binary.shdr_strtab.get_unsafe(sh.sh_name) {
+                Some(".eh_frame") => file.eh_frame = section(),
+                Some(".debug_frame") => file.debug_frame = section(),
+                Some(".text") => file.text_addr = sh.sh_addr,
+                _ => (),
+            }
+        }
+        Ok(file)
+    }
+}
+
+/// The ELF files of a process that were read so far, by their name in /proc/<pid>/maps.
+pub struct ElfFiles {
+    /// Prepended to the names to get the path of the files.
+    root: String,
+    files: RefCell<HashMap<String, Rc<ElfFile>>>,
+}
+
+impl ElfFiles {
+    /// Reads the files of the live process `pid` through its root, so that processes in
+    /// containers work.
+    pub fn for_process(pid: i32) -> ElfFiles {
+        ElfFiles::new(format!("/proc/{}/root", pid))
+    }
+
+    /// Reads the files from our own file system, e.g. for cores.
+    pub fn local() -> ElfFiles {
+        ElfFiles::new(String::new())
+    }
+
+    fn new(root: String) -> ElfFiles {
+        ElfFiles { root, files: RefCell::new(HashMap::new()) }
+    }
+
+    /// Returns the file `name`, reading it unless it was read before.
+    pub fn get(&self, name: &str) -> Result<Rc<ElfFile>, Box<dyn std::error::Error>> {
+        if let Some(file) = self.files.borrow().get(name) {
+            return Ok(file.clone());
+        }
+        let file = Rc::new(ElfFile::read(&format!("{}{}", self.root, name))?);
+        self.files.borrow_mut().insert(name.to_string(), file.clone());
+        Ok(file)
+    }
+
+    /// Forgets the files that `keep` returns false for, e.g. libraries that were unloaded.
+    pub fn retain<F: Fn(&str) -> bool>(&self, keep: F) {
+        self.files.borrow_mut().retain(|name, _| keep(name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads each file once, until it's forgotten.
+    #[test]
+    fn files_are_read_once() {
+        let exe = std::fs::read_link("/proc/self/exe").unwrap();
+        let exe = exe.to_str().unwrap();
+        let files = ElfFiles::for_process(std::process::id() as i32);
+        let file = files.get(exe).unwrap();
+        assert!(file.symbols.iter().any(|sym| sym.name.contains("files_are_read_once")));
+        assert!(file.eh_frame.is_some());
+        assert!(Rc::ptr_eq(&file, &files.get(exe).unwrap()));
+
+        files.retain(|name| name != exe);
+        assert!(!Rc::ptr_eq(&file, &files.get(exe).unwrap()));
+    }
+}
@@ -0,0 +1,166 @@
+//! Error type of this crate.
+
+use crate::procfs;
+use crate::thread_db::TdErr;
+
+/// Errors returned by this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// libthread_db could not be loaded or initialized.
+    Load(String),
+    /// A libthread_db function returned an error.
+    ThreadDb(TdErr),
+    /// A libthread_db function returned an error because accessing the process failed.
+    Callback(TdErr, CallbackError),
+    /// Reading the symbols of the target process failed.
+    Symbols(String),
+    /// Attaching to the target process failed.
+    Attach(AttachError),
+    /// Detaching from the target process failed, see `Process::detach()`.
+    Detach(std::io::Error),
+    /// Reading thread information from /proc failed.
+    Proc(std::io::Error),
+    /// Opening or parsing the core file failed, see `Library::attach_core()`.
+    Core(std::io::Error),
+    /// The operation isn't possible with the way the process is attached.
+    Unsupported(String),
+    /// The operation would modify a process attached read-only.
+    ReadOnly,
+    /// The process exited. Its pid may belong to another process by now.
+    Exited,
+    /// The process executed a new program. Attach again to inspect it.
+    Exec,
+    /// The process hasn't loaded the threading library (yet), see `Process::refresh()`.
+    NotThreaded,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Load(msg) => write!(f, "could not load libthread_db: {}", msg),
+            Error::ThreadDb(err) => write!(f, "libthread_db error: {:?}", err),
+            Error::Callback(err, cause) => write!(f, "libthread_db error: {:?}: {}", err, cause),
+            Error::Symbols(msg) => write!(f, "could not read symbols: {}", msg),
+            Error::Attach(err) => write!(f, "could not attach to process: {}", err),
+            Error::Detach(err) => write!(f, "could not detach from process: {}", err),
+            Error::Proc(err) => write!(f, "could not read /proc: {}", err),
+            Error::Core(err) => write!(f, "could not read core file: {}", err),
+            Error::Unsupported(msg) => write!(f, "unsupported operation: {}", msg),
+            Error::ReadOnly => write!(f, "process is attached read-only"),
+            Error::Exited => write!(f, "process has exited"),
+            Error::Exec => write!(f, "process has executed a new program"),
+            Error::NotThreaded => write!(f, "process has not loaded the threading library"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Callback(_, cause) => Some(cause),
+            Error::Attach(err) => Some(err),
+            Error::Detach(err) => Some(err),
+            Error::Proc(err) => Some(err),
+            Error::Core(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<TdErr> for Error {
+    fn from(err: TdErr) -> Error {
+        Error::ThreadDb(err)
+    }
+}
+
+/// A failed callback from libthread_db, e.g. a memory read from a process that exited.
+#[derive(Debug)]
+pub struct CallbackError {
+    /// Name of the callback, e.g. `ps_pdread`.
+    pub callback: &'static str,
+    /// The underlying error.
+    pub error: std::io::Error,
+}
+
+impl std::fmt::Display for CallbackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} failed: {}", self.callback, self.error)
+    }
+}
+
+impl std::error::Error for CallbackError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// A failed attempt to attach to a process or thread, with the likely cause.
+#[derive(Debug)]
+pub struct AttachError {
+    /// The error returned by the kernel.
+    pub error: std::io::Error,
+    /// Why the kernel refused, as far as we can tell.
+    pub cause: AttachCause,
+}
+
+impl AttachError {
+    /// Diagnoses the failure to attach to the process or thread `pid`.
+    pub(crate) fn new(pid: libc::pid_t, error: std::io::Error) -> AttachError {
+        let cause = procfs::diagnose_attach(pid, &error);
+        AttachError { error, cause }
+    }
+}
+
+impl std::fmt::Display for AttachError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.cause {
+            AttachCause::Unknown => write!(f, "{}", self.error),
+            ref cause => write!(f, "{}: {}", self.error, cause),
+        }
+    }
+}
+
+impl std::error::Error for AttachError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Why attaching with ptrace failed, see `AttachError`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttachCause {
+    /// The process doesn't exist or has exited.
+    Exited,
+    /// The process is already traced, e.g. by a debugger. Contains `TracerPid` from
+    /// /proc/<pid>/status, the ID of the tracing thread, which is the process ID for
+    /// single-threaded tracers.
+    AlreadyTraced(libc::pid_t),
+    /// Yama's `kernel.yama.ptrace_scope` sysctl forbids attaching. With 1, only descendants can be
+    /// traced, 2 requires CAP_SYS_PTRACE and 3 disables ptrace completely.
+    PtraceScope(u32),
+    /// The process runs as a different user and we don't have CAP_SYS_PTRACE.
+    DifferentUser {
+        uid: libc::uid_t,
+        target_uid: libc::uid_t,
+    },
+    /// The process isn't dumpable (e.g. a setuid program or after `prctl(PR_SET_DUMPABLE, 0)`)
+    /// and we don't have CAP_SYS_PTRACE.
+    NotDumpable,
+    /// None of the above.
+    Unknown,
+}
+
+impl std::fmt::Display for AttachCause {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AttachCause::Exited => write!(f, "the process does not exist or has exited"),
+            AttachCause::AlreadyTraced(tracer) => write!(f, "the process is already traced by pid {}", tracer),
+            AttachCause::PtraceScope(1) => write!(f, "kernel.yama.ptrace_scope is 1, so only descendants can be traced; run with CAP_SYS_PTRACE or set the sysctl to 0"),
+            AttachCause::PtraceScope(3) => write!(f, "kernel.yama.ptrace_scope is 3, so ptrace is disabled until reboot"),
+            AttachCause::PtraceScope(scope) => write!(f, "kernel.yama.ptrace_scope is {}, so tracing requires CAP_SYS_PTRACE", scope),
+            AttachCause::DifferentUser { uid, target_uid } => write!(f, "the process runs as uid {} but we run as uid {} without CAP_SYS_PTRACE", target_uid, uid),
+            AttachCause::NotDumpable => write!(f, "the process is not dumpable and we don't have CAP_SYS_PTRACE"),
+            AttachCause::Unknown => write!(f, "unknown cause"),
+        }
+    }
+}
@@ -1,66 +1,355 @@
+mod coredump;
+mod dl;
+mod elf;
+mod error;
 mod proc_service;
+mod procfs;
+mod symbols;
 mod thread_db;
+mod thread_info;
+mod unwind;
 
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Read;
-
-pub use thread_db::{TdErr, TdTaStats, TdThrInfo};
-use thread_db::{TdThrAgent, TdThrHandle, TdThrState};
-use proc_service::ProcHandle;
-
-use dlopen::wrapper::Container;
-
-/// Runs a libthread_db function, returning on error.
+use std::marker::PhantomData;
+use std::ops::ControlFlow;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+pub use error::{AttachCause, AttachError, CallbackError, Error};
+pub use proc_service::set_trace_calls;
+pub use procfs::{TaskStat, TaskState};
+pub use thread_db::{TdErr, TdTaStats, TdThrEvents, TdThrInfo, TdThrState, TdThrType, DEFAULT_LIB_NAMES};
+pub use thread_info::ThreadInfo;
+pub use unwind::Frame;
+use thread_db::{TdThrAgent, TdThrHandle};
+use proc_service::{Call, ProcHandle, ProcState};
+use symbols::{Symbol, SymbolIndex};
+
+use coredump::CoreFile;
+use dl::Namespace;
+use elf::ElfFiles;
+
+/// Runs a libthread_db function, returning on error. With a `ProcHandle`, the error includes why
+/// a callback failed during the call.
 macro_rules! td_try {
     ($e: expr) => {
         match $e {
             TdErr::Ok => (),
-            err => return Err(err),
+            err => return Err(err.into()),
+        }
+    };
+    ($handle: expr, $e: expr) => {{
+        let _call = prepare_call(&$handle)?;
+        match $e {
+            // The process may have exited or executed a new program during the call, so the
+            // results may be garbage even though the call succeeded.
+            TdErr::Ok => check_state(&$handle)?,
+            err => return Err(td_error(err, &$handle)),
         }
+    }};
+}
+
+/// Prepares a libthread_db call on the process: processes pending ptrace events once instead of on
+/// each of the many memory accesses of the call, and fails if the process is gone. The call is in
+/// progress until the returned guard is dropped.
+///
+/// Calls from a `for_each_thread()` callback are nested in the iteration and leave that to it,
+/// rather than processing events and forgetting callback errors in the middle of it.
+fn prepare_call(handle: &ProcHandle) -> Result<Call<'_>, Error> {
+    if !handle.in_call() {
+        handle.handle_events();
+        check_state(handle)?;
+        handle.clear_error();
+    }
+    Ok(handle.enter_call())
+}
+
+/// Returns the error for a failed libthread_db call, with the error of a failed callback if any.
+fn td_error(err: TdErr, handle: &ProcHandle) -> Error {
+    if let Err(e) = check_state(handle) {
+        return e;
+    }
+    match handle.take_error() {
+        Some(cause) => Error::Callback(err, cause),
+        None => Error::ThreadDb(err),
     }
 }
 
+/// Fails if the process exited or executed a new program since attaching.
+fn check_state(handle: &ProcHandle) -> Result<(), Error> {
+    match handle.state() {
+        ProcState::Alive => Ok(()),
+        ProcState::Exited => Err(Error::Exited),
+        ProcState::Exec => Err(Error::Exec),
+    }
+}
+
+/// A loaded libthread_db.
+///
+/// A `Library` can be shared between threads. Calls into libthread_db, which is not reentrant, are
+/// serialized internally. Cloning a `Library` is cheap, the clones share the loaded library.
+#[derive(Clone)]
 pub struct Library {
-    api: Container<thread_db::ThreadDb>,
+    api: Arc<thread_db::ThreadDb>,
 }
 
 impl Library {
+    /// Loads the system's libthread_db.
+    ///
+    /// Panics if the library cannot be loaded, see `try_new()` for a fallible variant.
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Library {
-        Library {
-            api: thread_db::open_lib(),
-        }
+        Library::try_new().expect("could not load libthread_db")
     }
 
-    pub fn attach(&self, pid: i32) -> Result<Process, TdErr> {
-        let symbols = match get_symbols(pid) {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("get_symbols: {:?}", e);
-                return Err(TdErr::Err);
+    /// Loads the system's libthread_db, trying each of `DEFAULT_LIB_NAMES` in order.
+    ///
+    /// Returns the error of the last attempt if none of them could be loaded.
+    pub fn try_new() -> Result<Library, Error> {
+        let mut last_err = None;
+        for name in DEFAULT_LIB_NAMES {
+            match Library::open(name) {
+                Ok(lib) => return Ok(lib),
+                Err(e) => last_err = Some(e),
             }
-        };
-        let mut handle = match ProcHandle::new(pid) {
-            Ok(h) => Box::new(h),
-            Err(e) => {
-                eprintln!("could not attach to process: {:?}", e);
-                return Err(TdErr::Err);
+        }
+        Err(last_err.unwrap_or_else(|| Error::Load("no library names to try".to_string())))
+    }
+
+    /// Loads the libthread_db at `path`.
+    ///
+    /// As with dlopen(3), a name without a slash is looked up in the default library search path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Library, Error> {
+        Ok(Library {
+            api: Arc::new(thread_db::open_lib(path.as_ref(), Namespace::Base)?),
+        })
+    }
+
+    /// Loads the libthread_db at `path` into a new link namespace with dlmopen(3).
+    ///
+    /// libthread_db is tied to the glibc version of the inspected process. Loading each copy into
+    /// its own namespace allows using libthread_dbs from different glibc versions side by side,
+    /// e.g. to inspect processes in several containers.
+    pub fn open_isolated<P: AsRef<Path>>(path: P) -> Result<Library, Error> {
+        Ok(Library {
+            api: Arc::new(thread_db::open_lib(path.as_ref(), Namespace::New)?),
+        })
+    }
+
+    /// Loads the libthread_db matching the glibc of the process with the given pid, trying the
+    /// entries of `DEFAULT_SEARCH_PATH`.
+    pub fn for_process(pid: i32) -> Result<Library, Error> {
+        Library::for_process_with_search_path(pid, DEFAULT_SEARCH_PATH)
+    }
+
+    /// Loads the libthread_db matching the glibc of the process with the given pid.
+    ///
+    /// Like gdb's `libthread-db-search-path`, each entry of `search_path` is either a directory
+    /// containing libthread_db or one of these special entries:
+    ///
+    ///  - `$pdir`: the directory of the target's libpthread (or libc with glibc 2.34+), accessed
+    ///    through `/proc/<pid>/root` so that processes in containers work.
+    ///  - `$sdir`: the system's libthread_db, as loaded by `try_new()`.
+    ///
+    /// The first library for which `td_ta_new` succeeds is returned. `td_ta_new` verifies that the
+    /// library's version matches the target's `nptl_version` and fails with `TdErr::Version`
+    /// otherwise. If no library matches, the error of the last library that could be loaded is
+    /// returned.
+    ///
+    /// The version can only be verified once the process loaded the threading library (see
+    /// `Process::is_threaded()`). Libraries that find it not threaded are skipped in favor of one
+    /// that verifies, since a libthread_db from before glibc 2.34 looks for the threading library
+    /// in libpthread and misses it in newer processes. If none verifies, the first of them is
+    /// returned; `Process::refresh()` then fails with `TdErr::Version` once the process is
+    /// threaded if it doesn't match.
+    pub fn for_process_with_search_path(pid: i32, search_path: &[&str]) -> Result<Library, Error> {
+        let mut last_err = None;
+        let mut unverified = None;
+        for candidate in search_candidates(pid, search_path) {
+            let lib = match Library::open(&candidate) {
+                Ok(lib) => lib,
+                Err(e) => {
+                    // Keep the error of a library that loaded, like `TdErr::Version`, which says
+                    // more than a missing candidate.
+                    last_err = last_err.or(Some(e));
+                    continue;
+                }
+            };
+            // Drop the trial Process right away to detach again.
+            let result = lib.attach(pid).map(|process| process.is_threaded());
+            match result {
+                Ok(true) => return Ok(lib),
+                Ok(false) => {
+                    log::debug!(target: "libthread_db::thread_db", pid = pid, path:% = candidate.display(); "process {} is not threaded, can't verify {}", pid, candidate.display());
+                    unverified = unverified.or(Some(lib));
+                }
+                // Other libraries won't help if we can't access the process at all.
+                Err(e @ Error::Attach(_)) | Err(e @ Error::Symbols(_)) => return Err(e),
+                Err(e) => {
+                    log::debug!(target: "libthread_db::thread_db", pid = pid, path:% = candidate.display(); "{} does not match: {}", candidate.display(), e);
+                    last_err = Some(e);
+                }
             }
+        }
+        unverified.ok_or_else(|| last_err.unwrap_or_else(|| Error::Load("empty search path".to_string())))
+    }
+
+    pub fn attach(&self, pid: i32) -> Result<Process<'_>, Error> {
+        self.attach_owned(pid)
+    }
+
+    /// Like `attach()`, but the returned `Process` doesn't borrow the `Library`. It keeps the
+    /// library loaded on its own, so that it can be stored in long-lived structs.
+    pub fn attach_owned(&self, pid: i32) -> Result<Process<'static>, Error> {
+        self.attach_with(pid, &AttachOptions::new())
+    }
+
+    /// Like `attach_owned()`, with the given options.
+    pub fn attach_with(&self, pid: i32, options: &AttachOptions) -> Result<Process<'static>, Error> {
+        let handle = if options.ptrace {
+            ProcHandle::new(pid)
+        } else {
+            ProcHandle::new_without_ptrace(pid)
         };
+        // Attach first: reading the symbols fails for the same reasons, but with a less helpful
+        // error.
+        let mut handle = Box::new(handle.map_err(|e| Error::Attach(AttachError::new(pid, e)))?);
+        handle.read_only |= options.read_only;
+        self.new_process(handle, ElfFiles::for_process(pid))
+    }
+
+    /// Opens the core file at `path` to inspect the threads of the dumped process as they were
+    /// when it crashed or was dumped with gcore(1).
+    ///
+    /// The parts of the memory that the kernel doesn't dump, like the read-only data of the
+    /// executable and libraries, are read from the mapped files, so these must still be at the
+    /// paths the process loaded them from. Use the libthread_db matching the glibc of the dumped
+    /// process, e.g. the system's for cores of local processes.
+    ///
+    /// The returned `Process` is read-only and never exits. Its `lwps()` are the threads in the
+    /// core. Methods that read `/proc` fail with `Error::Unsupported`.
+    pub fn attach_core<P: AsRef<Path>>(&self, path: P) -> Result<Process<'static>, Error> {
+        let core = CoreFile::open(path).map_err(Error::Core)?;
+        self.new_process(Box::new(ProcHandle::for_core(core)), ElfFiles::local())
+    }
+
+    /// Reads the symbols of the process of `handle` and initializes libthread_db for it.
+    fn new_process(&self, mut handle: Box<ProcHandle>, elf_files: ElfFiles) -> Result<Process<'static>, Error> {
+        let modules = mapped_modules(&handle).map_err(|e| Error::Symbols(e.to_string()))?;
+        let (symbols, symbol_index) = get_symbols(&handle, &elf_files).map_err(|e| Error::Symbols(e.to_string()))?;
         handle.symbols = symbols;
-        let mut ta: *mut TdThrAgent = std::ptr::null_mut();
-        unsafe {
-            // Initialize libthread_db.
-            td_try!(self.api.td_ta_new(handle.as_mut(), &mut ta));
+        let mut process = Process { lib: self.clone(), handle, ta: std::ptr::null_mut(), symbol_index, modules, elf_files, _marker: PhantomData };
+        process.new_agent()?;
+        Ok(process)
+    }
+}
+
+/// Options for `Library::attach_with()`.
+#[derive(Debug, Clone)]
+pub struct AttachOptions {
+    ptrace: bool,
+    read_only: bool,
+}
+
+impl AttachOptions {
+    /// Returns the options used by `Library::attach()`.
+    pub fn new() -> AttachOptions {
+        AttachOptions {
+            ptrace: true,
+            read_only: false,
         }
-        Ok(Process { lib: &self, handle, ta })
+    }
+
+    /// Whether to attach with ptrace, the default.
+    ///
+    /// Without ptrace, memory is read with process_vm_readv(2) or `/proc/<pid>/mem`, so processes
+    /// that are already traced by a debugger or sanitizer can be inspected. The process is never
+    /// stopped or modified, which means that reads may observe it in an inconsistent state.
+    /// Operations that need ptrace, like reading registers for backtraces, fail with
+    /// `Error::Unsupported`.
+    ///
+    /// *Note*: `Library::for_process()` briefly attaches with ptrace to find a matching
+    /// libthread_db. Use `Library::open()` with the target's libthread_db instead.
+    pub fn ptrace(mut self, ptrace: bool) -> AttachOptions {
+        self.ptrace = ptrace;
+        self
+    }
+
+    /// Guarantees that the process isn't modified. Always the case without ptrace.
+    ///
+    /// Memory and register writes requested by libthread_db are refused and logged, and methods
+    /// that might write fail with `Error::ReadOnly` before calling into libthread_db. Stopping
+    /// threads to read their registers is still allowed.
+    pub fn read_only(mut self, read_only: bool) -> AttachOptions {
+        self.read_only = read_only;
+        self
     }
 }
 
-/// Returns a map of mapped symbols in the process with the given pid.
-fn get_symbols(pid: i32) -> Result<HashMap<String, usize>, Box<std::error::Error>> {
+impl Default for AttachOptions {
+    fn default() -> AttachOptions {
+        AttachOptions::new()
+    }
+}
+
+/// Search path used by `Library::for_process()`.
+pub const DEFAULT_SEARCH_PATH: &[&str] = &["$pdir", "$sdir"];
+
+/// Returns the libraries to try for the given search path, in order.
+fn search_candidates(pid: i32, search_path: &[&str]) -> Vec<PathBuf> {
+    let mut candidates = Vec::new();
+    for entry in search_path {
+        let dir = match *entry {
+            "$sdir" => {
+                candidates.extend(DEFAULT_LIB_NAMES.iter().map(PathBuf::from));
+                continue;
+            }
+            "$pdir" => match thread_library_dir(pid) {
+                Some(dir) => PathBuf::from(format!("/proc/{}/root{}", pid, dir.display())),
+                None => continue,
+            },
+            dir => PathBuf::from(dir),
+        };
+        candidates.extend(DEFAULT_LIB_NAMES.iter().map(|name| dir.join(name)));
+    }
+    candidates.dedup();
+    candidates
+}
+
+/// Returns the directory of the threading library (libpthread, or libc if the process doesn't load
+/// a separate libpthread) mapped in the process with the given pid.
+fn thread_library_dir(pid: i32) -> Option<PathBuf> {
+    let maps = proc_maps::get_process_maps(pid).ok()?;
+    let paths: Vec<&Path> = maps.iter()
+        .filter_map(|map| map.filename().as_ref())
+        .map(Path::new)
+        .collect();
+    let has_prefix = |path: &Path, prefix: &str| {
+        path.file_name().is_some_and(|name| name.to_string_lossy().starts_with(prefix))
+    };
+    paths.iter()
+        .find(|path| has_prefix(path, "libpthread"))
+        .or_else(|| paths.iter().find(|path| has_prefix(path, "libc.so") || has_prefix(path, "libc-")))
+        .and_then(|path| path.parent())
+        .map(Path::to_path_buf)
+}
+
+/// Returns the start address and file name of the executable and libraries mapped in the process,
+/// to tell whether it loaded or unloaded any since.
+fn mapped_modules(handle: &ProcHandle) -> Result<Vec<(usize, String)>, Box<dyn std::error::Error>> {
+    Ok(handle.mappings()?.into_iter()
+        .filter(|map| map.offset == 0 && map.name.starts_with('/'))
+        .map(|map| (map.start, map.name))
+        .collect())
+}
+
+/// Returns a map of mapped symbols in the process, and an index of the functions and objects for
+/// looking up addresses. Reads the files that `files` doesn't have yet.
+fn get_symbols(handle: &ProcHandle, files: &ElfFiles) -> Result<(HashMap<String, usize>, SymbolIndex), Box<dyn std::error::Error>> {
     // Result map.
     let mut symbols = HashMap::new();
+    let mut index = SymbolIndex::default();
 
     // The mappings for libpthread look like this:
     //
@@ -77,155 +366,541 @@ fn get_symbols(pid: i32) -> Result<HashMap<String, usize>, Box<std::error::Error
     // understand any mappings other than the first (with offset 0).
     //
     // See also this Stackoverflow question: https://stackoverflow.com/questions/25274569/
-    for map in proc_maps::get_process_maps(pid)? {
+    let maps = handle.mappings()?;
+    for map in &maps {
         // We're only interested in the first entry for each library.
-        if map.offset > 0 || map.filename().is_none() {
+        if map.offset > 0 {
             continue;
         }
-        let filename = map.filename().as_ref().unwrap();
         // We can only read files, skip mappings to [stack] etc.
-        if !filename.starts_with("/") {
+        if !map.name.starts_with('/') {
             continue;
         }
 
-        for (symbol, offset) in get_symbols_for_library(&filename)? {
-            symbols.insert(symbol.to_string(), offset + map.start());
-        }
-    }
-    Ok(symbols)
-}
-
-/// Returns a map with all symbols defined in the given library.
-fn get_symbols_for_library(filename: &str) -> Result<HashMap<String, usize>, Box<std::error::Error>> {
-    let mut symbols = HashMap::new();
-    eprintln!("reading library {}", filename);
-
-    let mut f = match File::open(filename) {
-        Ok(f) => f,
-        // 
-        Err(e) => {
-            use std::error::Error;
-            eprintln!("get_symbols_for_library: couldn't read {}: {}", filename, e.description());
-            return Ok(symbols);
-        }
-    };
-    let mut buf: Vec<u8> = Vec::new();
-    f.read_to_end(&mut buf)?;
-
-    let binary = goblin::elf::Elf::parse(&buf)?;
-    for sym in binary.syms.iter() {
-        if let Some(name) = binary.strtab.get_unsafe(sym.st_name) {
+        let file = files.get(&map.name)?;
+        // The first mapping holds the start of the first loadable segment, which is at 0 for
+        // libraries and position-independent executables.
+        let bias = map.start.wrapping_sub(file.base);
+        let mut indexed = Vec::new();
+        for sym in &file.symbols {
+            let address = sym.value.wrapping_add(bias);
             // Only keep symbols that start with a letter to keep the symbol hashmap small.
-            let first_char = name.chars().next().unwrap_or('\0');
+            let first_char = sym.name.chars().next().unwrap_or('\0');
             if first_char.is_alphabetic() || first_char == '_' {
-                symbols.insert(name.to_string(), sym.st_value as usize);
+                symbols.insert(sym.name.clone(), address);
+            }
+            if sym.indexed {
+                indexed.push(Symbol { name: sym.name.clone(), address, size: sym.size });
             }
         }
+        let end = maps.iter()
+            .filter(|other| other.name == map.name)
+            .map(|other| other.end)
+            .max()
+            .unwrap_or(map.end);
+        index.add_module(map.name.clone(), map.start..end, indexed);
     }
-    Ok(symbols)
+    Ok((symbols, index))
 }
 
+/// A process attached with ptrace, or a core opened with `Library::attach_core()`.
+///
+/// The kernel only accepts ptrace requests from the thread that attached to a process, so a
+/// `Process` (and its `Thread`s) can't be sent to or shared with other threads. To inspect
+/// processes from a thread pool, share the `Library` and attach from the worker threads.
 pub struct Process<'a> {
-    lib: &'a Library,
+    lib: Library,
     // handle needs to be boxed so that the pointer that libthread_db keeps stays valid even if
     // Process is moved on the Rust side.
     handle: Box<ProcHandle>,
+    // Null until the process loaded the threading library, see `refresh()`.
     ta: *mut TdThrAgent,
+    symbol_index: SymbolIndex,
+    // Modules mapped when the symbols were read.
+    modules: Vec<(usize, String)>,
+    // Files of the modules, shared by the symbols and backtraces.
+    elf_files: ElfFiles,
+    // Processes from `Library::attach()` borrow the library for API compatibility.
+    _marker: PhantomData<&'a Library>,
 }
 
-impl Process<'_> {
+impl<'a> Process<'a> {
     /// Get number of currently running threads in process associated with TA.
-    pub fn get_nthreads(&self) -> Result<i32, TdErr> {
+    pub fn get_nthreads(&self) -> Result<i32, Error> {
+        let ta = self.agent()?;
         let mut result: i32 = 42;
         unsafe {
-            td_try!(self.lib.api.td_ta_get_nthreads(self.ta, &mut result));
+            td_try!(self.handle, self.lib.api.td_ta_get_nthreads(ta, &mut result));
         }
         Ok(result)
     }
 
+    /// Whether the process loaded the threading library, so that its threads can be inspected.
+    ///
+    /// Processes that load it late (e.g. with glibc before 2.34, when a plugin linked against
+    /// libpthread is loaded with `dlopen()`) can be attached before. Until then, methods that need
+    /// libthread_db fail with `Error::NotThreaded`; call `refresh()` to check again.
+    pub fn is_threaded(&self) -> bool {
+        !self.ta.is_null()
+    }
+
+    /// Picks up libraries the process loaded or unloaded since attaching, and initializes
+    /// libthread_db if the threading library is among them. Returns `is_threaded()`.
+    ///
+    /// Only re-reads the symbols if the mapped modules changed, so it's cheap to call
+    /// periodically, e.g. before each `threads()`.
+    pub fn refresh(&mut self) -> Result<bool, Error> {
+        self.check_state()?;
+        let modules = mapped_modules(&self.handle).map_err(|e| Error::Symbols(e.to_string()))?;
+        if modules != self.modules {
+            log::debug!(target: "libthread_db::symbols", pid = self.handle.pid; "mapped modules of process {} changed, reading symbols", self.handle.pid);
+            self.elf_files.retain(|name| modules.iter().any(|(_, module)| module == name));
+            let (symbols, symbol_index) = get_symbols(&self.handle, &self.elf_files).map_err(|e| Error::Symbols(e.to_string()))?;
+            self.handle.symbols = symbols;
+            self.symbol_index = symbol_index;
+            self.modules = modules;
+            if self.ta.is_null() {
+                self.new_agent()?;
+            }
+        }
+        Ok(self.is_threaded())
+    }
+
+    /// Initializes libthread_db for the process, unless it hasn't loaded the threading library.
+    fn new_agent(&mut self) -> Result<(), Error> {
+        let mut ta: *mut TdThrAgent = std::ptr::null_mut();
+        // Nothing can call into libthread_db during td_ta_new, so the call needn't stay marked as
+        // in progress.
+        prepare_call(&self.handle)?;
+        match unsafe { self.lib.api.td_ta_new(self.handle.as_mut(), &mut ta) } {
+            TdErr::Ok => self.ta = ta,
+            TdErr::NoLibthread => {
+                log::debug!(target: "libthread_db::thread_db", pid = self.handle.pid; "process {} has not loaded the threading library", self.handle.pid);
+            },
+            err => return Err(td_error(err, &self.handle)),
+        }
+        Ok(())
+    }
+
+    /// Returns the thread agent, or fails if the process hasn't loaded the threading library.
+    fn agent(&self) -> Result<*mut TdThrAgent, Error> {
+        if self.ta.is_null() {
+            Err(Error::NotThreaded)
+        } else {
+            Ok(self.ta)
+        }
+    }
+
+    /// Detaches from the process, reporting errors that dropping the `Process` only logs.
+    ///
+    /// Frees libthread_db's data for the process and detaches from all threads. Both are
+    /// attempted even if the first fails; the first error is returned.
+    pub fn detach(mut self) -> Result<(), Error> {
+        let deleted = self.delete_agent();
+        let detached = self.handle.detach().map_err(Error::Detach);
+        deleted.and(detached)
+    }
+
+    /// Frees libthread_db's data for the process, once.
+    fn delete_agent(&mut self) -> Result<(), Error> {
+        let ta = std::mem::replace(&mut self.ta, std::ptr::null_mut());
+        if !ta.is_null() {
+            unsafe {
+                td_try!(self.lib.api.td_ta_delete(ta));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails with `Error::Exited` or `Error::Exec` if the process exited or executed a new program
+    /// since attaching.
+    ///
+    /// All other methods fail the same way afterwards. Exits are detected reliably, even if the
+    /// pid is reused, but executing a new program is only detected when attached with ptrace.
+    pub fn check_state(&self) -> Result<(), Error> {
+        check_state(&self.handle)
+    }
+
+    /// Returns the process ID, for cores the ID the dumped process had.
+    pub fn pid(&self) -> i32 {
+        self.handle.pid
+    }
+
+    /// Whether the process is a core, see `Library::attach_core()`.
+    pub fn is_core(&self) -> bool {
+        self.handle.core().is_some()
+    }
+
+    /// Fails with `Error::Unsupported` for cores, which have no `/proc` entries to read `what`
+    /// from.
+    fn check_live(&self, what: &str) -> Result<(), Error> {
+        if self.is_core() {
+            return Err(Error::Unsupported(format!("{} can't be read from cores", what)));
+        }
+        Ok(())
+    }
+
+    /// Whether the process is attached read-only, see `AttachOptions::read_only()`.
+    pub fn is_read_only(&self) -> bool {
+        self.handle.read_only
+    }
+
+    /// Fails for processes attached read-only.
+    fn check_writable(&self) -> Result<(), Error> {
+        if self.handle.read_only {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
     /// Enable collecting statistics for process associated with TA.
     /// *Note*: Not implemented in glibc.
-    pub fn enable_stats(&mut self, enable: bool) -> Result<(), TdErr> {
+    pub fn enable_stats(&mut self, enable: bool) -> Result<(), Error> {
+        self.check_writable()?;
+        let ta = self.agent()?;
         unsafe {
-            td_try!(self.lib.api.td_ta_enable_stats(self.ta, enable as i32));
+            td_try!(self.handle, self.lib.api.td_ta_enable_stats(ta, enable as i32));
         }
         Ok(())
     }
 
     /// Reset statistics.
     /// *Note*: Not implemented in glibc.
-    pub fn reset_stats(&mut self) -> Result<(), TdErr> {
+    pub fn reset_stats(&mut self) -> Result<(), Error> {
+        self.check_writable()?;
+        let ta = self.agent()?;
         unsafe {
-            td_try!(self.lib.api.td_ta_reset_stats(self.ta));
+            td_try!(self.handle, self.lib.api.td_ta_reset_stats(ta));
         }
         Ok(())
     }
 
     /// Retrieve statistics from process associated with TA.
     /// *Note*: Not implemented in glibc.
-    pub fn get_stats(&self) -> Result<TdTaStats, TdErr> {
+    pub fn get_stats(&self) -> Result<TdTaStats, Error> {
+        let ta = self.agent()?;
         let mut result: TdTaStats = Default::default();
         unsafe {
-            td_try!(self.lib.api.td_ta_get_stats(self.ta, &mut result));
+            td_try!(self.handle, self.lib.api.td_ta_get_stats(ta, &mut result));
         }
         Ok(result)
     }
 
+    /// Returns the module, symbol name and offset into the symbol for an address in the process,
+    /// e.g. a `Frame::pc` or `ThreadInfo::start_func`.
+    ///
+    /// Uses the ELF symbol tables of the modules mapped when attaching. Modules loaded later are
+    /// only known after `refresh()`, and there is no DWARF line information.
+    pub fn symbolize(&self, addr: usize) -> Option<(&str, &str, usize)> {
+        self.symbol_index.lookup(addr)
+    }
+
+    /// Returns the kernel thread IDs of all threads attached with ptrace, sorted.
+    ///
+    /// Threads created after attaching are attached automatically. The kernel stops them until
+    /// the next operation on the process, so call this (or any other method) from time to time
+    /// to let new threads and threads that received signals continue. Empty for processes
+    /// attached without ptrace, and the threads in the core for cores.
+    pub fn lwps(&self) -> Vec<libc::pid_t> {
+        self.handle.handle_events();
+        self.handle.tasks()
+    }
+
     /// Get all threads.
-    pub fn threads(&self) -> Result<Vec<Thread>, TdErr> {
-        // The td_ta_thr_iter function will call the callback function for each thread. Save the
-        // results in a Vec so that we can iterate over it.
-        let mut handles: Vec<TdThrHandle> = Vec::new();
-        unsafe {
-            let sigmask = nix::sys::signal::SigSet::empty();
-            let mut c_sigmask = sigmask.as_ref().clone();
-            td_try!(self.lib.api.td_ta_thr_iter(self.ta, thr_iter_callback, &mut handles as *mut _ as *mut libc::c_void, TdThrState::AnyState, 0, &mut c_sigmask, 0));
+    pub fn threads(&self) -> Result<Vec<Thread<'_>>, Error> {
+        let mut threads = Vec::new();
+        self.for_each_thread(&ThreadFilter::new(), |thread| {
+            threads.push(thread);
+            ControlFlow::Continue(())
+        })?;
+        Ok(threads)
+    }
+
+    /// Calls `f` for each thread matching the filter, until it returns `ControlFlow::Break`.
+    ///
+    /// Unlike `threads()`, this doesn't collect all thread handles first.
+    pub fn for_each_thread<'p, F>(&'p self, filter: &ThreadFilter, mut f: F) -> Result<(), Error>
+        where F: FnMut(Thread<'p>) -> ControlFlow<()>
+    {
+        let ta = self.agent()?;
+        let mut state = ThrIterState { process: self, callback: &mut f, stopped: false, panic: None };
+        let mut c_sigmask = *filter.sigmask.as_ref();
+        let _call = prepare_call(&self.handle)?;
+        let result = unsafe {
+            self.lib.api.td_ta_thr_iter(ta, thr_iter_callback, &mut state as *mut _ as *mut libc::c_void, filter.state, filter.min_priority, &mut c_sigmask, filter.user_flags)
+        };
+        if let Some(panic) = state.panic {
+            std::panic::resume_unwind(panic);
+        }
+        match result {
+            TdErr::Ok => (),
+            // libthread_db reports a stop requested by the callback as an error.
+            TdErr::DbErr if state.stopped => (),
+            err => return Err(td_error(err, &self.handle)),
         }
-        Ok(handles.iter().map(|handle| Thread { lib: self.lib, handle: *handle }).collect())
+        // Threads visited after an exit or exec are garbage, see `td_try!`.
+        check_state(&self.handle)
+    }
+
+    /// Returns the first thread matching both the filter and the predicate.
+    pub fn find_thread<P>(&self, filter: &ThreadFilter, mut predicate: P) -> Result<Option<Thread<'_>>, Error>
+        where P: FnMut(&Thread) -> bool
+    {
+        let mut found = None;
+        self.for_each_thread(filter, |thread| {
+            if predicate(&thread) {
+                found = Some(thread);
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        })?;
+        Ok(found)
+    }
+}
+
+/// Selects the threads visited by `Process::for_each_thread()`, see `td_ta_thr_iter`.
+///
+/// *Note*: glibc only implements the priority filter.
+#[derive(Clone)]
+pub struct ThreadFilter {
+    state: TdThrState,
+    min_priority: i32,
+    sigmask: nix::sys::signal::SigSet,
+    user_flags: u32,
+}
+
+impl ThreadFilter {
+    /// Returns a filter matching all threads.
+    pub fn new() -> ThreadFilter {
+        ThreadFilter {
+            state: TdThrState::AnyState,
+            min_priority: 0,
+            sigmask: nix::sys::signal::SigSet::empty(),
+            user_flags: 0,
+        }
+    }
+
+    /// Only match threads in the given state.
+    pub fn state(mut self, state: TdThrState) -> ThreadFilter {
+        self.state = state;
+        self
+    }
+
+    /// Only match threads with at least the given priority.
+    pub fn min_priority(mut self, priority: i32) -> ThreadFilter {
+        self.min_priority = priority;
+        self
+    }
+
+    /// Only match threads with the given signal mask.
+    pub fn sigmask(mut self, sigmask: nix::sys::signal::SigSet) -> ThreadFilter {
+        self.sigmask = sigmask;
+        self
+    }
+
+    /// Only match threads with the given user flags.
+    pub fn user_flags(mut self, user_flags: u32) -> ThreadFilter {
+        self.user_flags = user_flags;
+        self
+    }
+}
+
+impl Default for ThreadFilter {
+    fn default() -> ThreadFilter {
+        ThreadFilter::new()
     }
+}
 
+/// Data passed to `thr_iter_callback`.
+struct ThrIterState<'p, 'f> {
+    process: &'p Process<'p>,
+    callback: &'f mut dyn FnMut(Thread<'p>) -> ControlFlow<()>,
+    /// Whether the callback requested to stop.
+    stopped: bool,
+    /// Panic in the callback, resumed after td_ta_thr_iter returns.
+    panic: Option<Box<dyn std::any::Any + Send>>,
 }
 
-/// Appends the thread handle to the Vec<Process> in cbdata.
+/// Calls the callback in the ThrIterState in cbdata with the thread.
 unsafe extern "C" fn thr_iter_callback(handle: *const TdThrHandle, cbdata: *mut libc::c_void) -> i32 {
-    let threads = cbdata as *mut Vec<TdThrHandle>;
-    (*threads).push(*handle);
-    0
+    let state = &mut *(cbdata as *mut ThrIterState);
+    let thread = Thread { process: state.process, handle: *handle };
+    // Don't unwind into libthread_db.
+    match std::panic::catch_unwind(AssertUnwindSafe(|| (state.callback)(thread))) {
+        Ok(ControlFlow::Continue(())) => 0,
+        Ok(ControlFlow::Break(())) => {
+            state.stopped = true;
+            1
+        },
+        Err(panic) => {
+            state.panic = Some(panic);
+            1
+        },
+    }
 }
 
 impl Drop for Process<'_> {
     fn drop(&mut self) {
-        unsafe {
-            match self.lib.api.td_ta_delete(self.ta) {
-                TdErr::Ok => (),
-                err => panic!("Deleting Process with pid {} failed: {:?}", self.handle.pid, err),
-            }
+        // Panicking while unwinding would abort, so only log errors. Use `detach()` to handle them.
+        // The handle detaches from the process when it's dropped.
+        if let Err(e) = self.delete_agent() {
+            log::warn!(target: "libthread_db::thread_db", pid = self.handle.pid; "deleting the thread agent of process {} failed: {}", self.handle.pid, e);
         }
     }
 }
 
-pub struct Thread<'a> {
-    lib: &'a Library,
+/// A thread of a `Process`.
+///
+/// The thread handle points into the thread agent of its process, so a `Thread` borrows the
+/// `Process` and can't outlive it:
+///
+/// ```compile_fail,E0505
+/// # let pid = 0;
+/// let lib = libthread_db::Library::new();
+/// let process = lib.attach(pid).unwrap();
+/// let threads = process.threads().unwrap();
+/// drop(process);
+/// threads[0].info().unwrap();
+/// ```
+///
+/// ```compile_fail,E0515
+/// # use libthread_db::{Library, Thread};
+/// fn first_thread(lib: &Library, pid: i32) -> Thread<'static> {
+///     let process = lib.attach_owned(pid).unwrap();
+///     process.threads().unwrap().remove(0)
+/// }
+/// ```
+///
+/// Threads can be used while the process is alive:
+///
+/// ```no_run
+/// # let pid = 0;
+/// let lib = libthread_db::Library::new();
+/// let process = lib.attach(pid).unwrap();
+/// for thread in process.threads().unwrap() {
+///     println!("{}: {:?}", thread.name().unwrap(), thread.info().unwrap());
+/// }
+/// drop(process);
+/// ```
+pub struct Thread<'p> {
+    process: &'p Process<'p>,
     handle: TdThrHandle,
 }
 
 impl Thread<'_> {
     /// Validate that this is a thread handle.
-    pub fn validate(&self) -> Result<(), TdErr> {
+    pub fn validate(&self) -> Result<(), Error> {
         unsafe {
-            td_try!(self.lib.api.td_thr_validate(&self.handle));
+            td_try!(self.process.handle, self.process.lib.api.td_thr_validate(&self.handle));
         }
         Ok(())
     }
 
     /// Return information about the thread.
-    pub fn info(&self) -> Result<TdThrInfo, TdErr> {
+    pub fn info(&self) -> Result<TdThrInfo, Error> {
         unsafe {
             let mut info: TdThrInfo = std::mem::zeroed();
-            td_try!(self.lib.api.td_thr_get_info(&self.handle, &mut info));
+            td_try!(self.process.handle, self.process.lib.api.td_thr_get_info(&self.handle, &mut info));
             Ok(info)
         }
     }
+
+    /// Return information about the thread as an owned `ThreadInfo`, including the kernel's
+    /// scheduling information if available.
+    pub fn thread_info(&self) -> Result<ThreadInfo, Error> {
+        let mut info = ThreadInfo::from(&self.info()?);
+        if let Some(core) = self.process.handle.core() {
+            // See `name()`.
+            if info.lwp == core.pid {
+                info.name = Some(core.fname.clone());
+            }
+            return Ok(info);
+        }
+        let pid = self.process.handle.pid;
+        info.name = match procfs::read_comm(pid, info.lwp) {
+            Ok(name) => Some(name),
+            Err(e) => {
+                log::debug!(target: "libthread_db::proc_service", pid = pid, lwp = info.lwp; "couldn't read thread name: {}", e);
+                None
+            }
+        };
+        info.task = match procfs::TaskStat::read(pid, info.lwp) {
+            Ok(task) => Some(task),
+            Err(e) => {
+                log::debug!(target: "libthread_db::proc_service", pid = pid, lwp = info.lwp; "couldn't read task stat: {}", e);
+                None
+            }
+        };
+        // Make sure the pid wasn't reused while reading /proc.
+        self.process.check_state()?;
+        Ok(info)
+    }
+
+    /// Return the name of the thread, as set with pthread_setname_np(3), from
+    /// `/proc/<pid>/task/<lwp>/comm`.
+    ///
+    /// For cores, only the name of the main thread is known, from the core's NT_PRPSINFO note.
+    /// Other threads fail with `Error::Unsupported`: the kernel doesn't save their names in the
+    /// core, and glibc's `struct pthread` has no name field that could be read from the dumped
+    /// memory instead.
+    pub fn name(&self) -> Result<String, Error> {
+        let lwp = self.info()?.ti_lid;
+        if let Some(core) = self.process.handle.core() {
+            if lwp == core.pid {
+                return Ok(core.fname.clone());
+            }
+            return Err(Error::Unsupported("cores only contain the name of the main thread".to_string()));
+        }
+        let name = procfs::read_comm(self.process.handle.pid, lwp);
+        self.process.check_state()?;
+        name.map_err(Error::Proc)
+    }
+
+    /// Unwind the thread's stack, innermost frame first.
+    ///
+    /// The thread is stopped while its registers are read through libthread_db and the stack is
+    /// unwound with the call frame information of each module, falling back to the frame pointer
+    /// chain. For cores, the registers saved in the core's NT_PRSTATUS note of the thread are
+    /// used.
+    ///
+    /// *Note*: Only x86-64 is supported. Processes attached without ptrace fail with
+    /// `Error::Unsupported`.
+    pub fn backtrace(&self) -> Result<Vec<Frame>, Error> {
+        let handle = &self.process.handle;
+        if !handle.ptrace && !self.process.is_core() {
+            return Err(Error::Unsupported("reading registers requires ptrace".to_string()));
+        }
+        let lwp = self.info()?.ti_lid;
+        // Threads in cores don't run anymore.
+        let _stop = if self.process.is_core() {
+            None
+        } else {
+            Some(handle.stop_lwp(lwp).map_err(|e| match check_state(handle) {
+                Ok(()) => Error::Attach(AttachError::new(lwp, e)),
+                Err(state) => state,
+            })?)
+        };
+        unsafe {
+            let mut regs: libc::user_regs_struct = std::mem::zeroed();
+            td_try!(self.process.handle, self.process.lib.api.td_thr_getgregs(&self.handle, &mut regs));
+            unwind::backtrace(handle, &self.process.elf_files, &regs).map_err(Error::Proc)
+        }
+    }
+
+    /// Return the kernel's scheduling information about the thread from
+    /// `/proc/<pid>/task/<lwp>/stat` and `status`.
+    ///
+    /// Unlike `info().ti_state`, which glibc always reports as active, this tells running,
+    /// sleeping and stopped threads apart.
+    pub fn task_stat(&self) -> Result<TaskStat, Error> {
+        self.process.check_live("scheduling information")?;
+        let lwp = self.info()?.ti_lid;
+        let task = procfs::TaskStat::read(self.process.handle.pid, lwp);
+        self.process.check_state()?;
+        task.map_err(Error::Proc)
+    }
 }
 
 
@@ -249,8 +924,9 @@ mod tests {
             },
             ForkResult::Parent { child, .. } => {
                 let pid = child.as_raw();
-                let symbols = get_symbols(pid as i32).expect("could not get symbols");
-                let gdb_symbols = get_symbols_gdb(pid as i32).expect("could not get gdb symbols");
+                let handle = ProcHandle::new_without_ptrace(pid).unwrap();
+                let (symbols, _) = get_symbols(&handle, &ElfFiles::for_process(pid)).expect("could not get symbols");
+                let gdb_symbols = get_symbols_gdb(pid).expect("could not get gdb symbols");
                 println!("#symbols = {}, #gdb_symbols = {}", symbols.len(), gdb_symbols.len());
                 let mut checked_symbols = 0;
                 for (symbol, offset) in gdb_symbols {
@@ -264,7 +940,54 @@ mod tests {
         }
     }
 
-    fn get_symbols_gdb(pid: i32) -> Result<HashMap<String, usize>, Box<std::error::Error>> {
+    /// Expands the special entries of the search path.
+    #[test]
+    fn test_search_candidates() {
+        let pid = std::process::id() as i32;
+        let candidates = search_candidates(pid, &["$pdir", "/opt/glibc/lib", "$sdir"]);
+        let libc_dir = thread_library_dir(pid).expect("no libc mapped");
+        assert_eq!(candidates, vec![
+            PathBuf::from(format!("/proc/{}/root{}/libthread_db.so.1", pid, libc_dir.display())),
+            PathBuf::from(format!("/proc/{}/root{}/libthread_db.so", pid, libc_dir.display())),
+            PathBuf::from("/opt/glibc/lib/libthread_db.so.1"),
+            PathBuf::from("/opt/glibc/lib/libthread_db.so"),
+            PathBuf::from("libthread_db.so.1"),
+            PathBuf::from("libthread_db.so"),
+        ]);
+    }
+
+    /// Attaches to a process before it loads the threading library, simulated by hiding its
+    /// symbols, and initializes libthread_db on refresh.
+    #[test]
+    fn late_threading_library_works() {
+        use nix::unistd::{fork, ForkResult};
+
+        match fork().unwrap() {
+            ForkResult::Child => {
+                std::thread::sleep(std::time::Duration::from_millis(2000));
+                std::process::exit(0);
+            },
+            ForkResult::Parent { child, .. } => {
+                let lib = Library::new();
+                let mut process = lib.attach(child.as_raw()).unwrap();
+                assert!(process.is_threaded());
+                process.delete_agent().unwrap();
+                process.handle.symbols.clear();
+                process.modules.clear();
+                process.new_agent().unwrap();
+                assert!(!process.is_threaded());
+                assert!(matches!(process.threads(), Err(Error::NotThreaded)));
+                assert!(matches!(process.get_nthreads(), Err(Error::NotThreaded)));
+
+                assert!(process.refresh().unwrap());
+                assert_eq!(process.threads().unwrap().len(), 1);
+                // Nothing changed since.
+                assert!(process.refresh().unwrap());
+            }
+        }
+    }
+
+    fn get_symbols_gdb(pid: i32) -> Result<HashMap<String, usize>, Box<dyn std::error::Error>> {
         let mut result = HashMap::new();
         eprintln!("starting gdb");
         let child = Command::new("gdb")
@@ -276,7 +999,7 @@ mod tests {
 
         let reader = BufReader::new(child.stdout.unwrap());
 
-        for line in reader.lines().filter_map(|line| line.ok()) {
+        for line in reader.lines().map_while(Result::ok) {
             let tokens: Vec<&str> = line.split_whitespace().collect();
             // Filter unrelated gdb output by searching for lines with a number and some other
             // word.
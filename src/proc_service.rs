@@ -1,19 +1,36 @@
-/// Callback interface for libthread_db.
-///
-/// See /usr/include/proc_service.h
+//! Callback interface for libthread_db.
+//!
+//! See /usr/include/proc_service.h
 
+use std::cell::{Cell, RefCell};
 use std::ffi::CStr;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
 use errno::{errno, set_errno, Errno};
+use log::{debug, trace, warn};
+
+use crate::coredump::CoreFile;
+use crate::error::CallbackError;
+use crate::procfs;
 
 pub type PsAddr = libc::c_void;
 
-const TRACE_CALLS: bool = true;
+/// Whether calls from libthread_db are logged, see `set_trace_calls()`.
+static TRACE_CALLS: AtomicBool = AtomicBool::new(false);
+
+/// Enables or disables logging every call from libthread_db at trace level.
+pub fn set_trace_calls(enabled: bool) {
+    TRACE_CALLS.store(enabled, Ordering::Relaxed);
+}
 
+/// Logs a callback and its result if call tracing is enabled.
 macro_rules! ps_trace {
     ($($arg:tt)*) => (
-        if TRACE_CALLS {
-            eprintln!($($arg)*);
+        if TRACE_CALLS.load(Ordering::Relaxed) {
+            trace!($($arg)*);
         }
     )
 }
@@ -41,31 +58,503 @@ pub enum PsErr {
 pub struct ProcHandle {
     pub pid: i32,
     pub symbols: HashMap<String, usize>,
+    /// Whether the process is attached with ptrace. Otherwise, memory is only read and the process
+    /// is never stopped.
+    pub ptrace: bool,
+    /// Whether writes to memory and registers are refused. Always set without ptrace.
+    pub read_only: bool,
+    /// Threads attached with ptrace, or the threads of a core.
+    tasks: RefCell<BTreeSet<libc::pid_t>>,
+    /// Thread held stopped by `stop_lwp()`. Memory is accessed through it instead of stopping the
+    /// process again.
+    stopped_lwp: Cell<Option<libc::pid_t>>,
+    /// Error of the last failed callback, see `take_error()`.
+    last_error: RefCell<Option<CallbackError>>,
+    /// Refers to the process even after its pid is reused. `None` on kernels before 5.3.
+    pidfd: Option<OwnedFd>,
+    /// See `state()`.
+    state: Cell<ProcState>,
+    /// Number of libthread_db calls in progress, see `enter_call()`.
+    calls: Cell<usize>,
+    /// Memory and registers of a dumped process, see `for_core()`.
+    core: Option<CoreFile>,
+}
+
+/// Whether the process is still the one that was attached, see `ProcHandle::state()`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ProcState {
+    Alive,
+    /// The process exited. Its pid may belong to another process by now.
+    Exited,
+    /// The process executed a new program, so symbol addresses and libthread_db's data are stale.
+    Exec,
+}
+
+/// Opens a pidfd for the process, see pidfd_open(2).
+fn pidfd_open(pid: libc::pid_t) -> Option<OwnedFd> {
+    match unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) } {
+        -1 => {
+            debug!(pid = pid; "pidfd_open failed: {}", errno());
+            None
+        }
+        fd => Some(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) }),
+    }
+}
+
+/// Attaches to a single thread without stopping it, following threads it creates.
+fn seize(tid: libc::pid_t) -> Result<(), std::io::Error> {
+    unsafe {
+        let options = libc::PTRACE_O_TRACECLONE | libc::PTRACE_O_TRACEEXEC;
+        if libc::ptrace(libc::PTRACE_SEIZE, tid, std::ptr::null::<libc::c_void>(), options as *const libc::c_void) == -1 {
+            return Err(std::io::Error::from(errno::errno()));
+        }
+    }
+    Ok(())
+}
+
+/// Why a traced thread is in a ptrace-stop, see "Stopped states" in ptrace(2).
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Stop {
+    /// Stopped by PTRACE_INTERRUPT or after being attached.
+    Interrupt,
+    /// About to receive the signal, which is delivered when resuming.
+    Signal(libc::c_int),
+    /// The process is stopped by a job control signal like SIGSTOP. Stays stopped when resuming.
+    Group(libc::c_int),
+    /// Created the thread with the given ID.
+    Clone(libc::pid_t),
+    /// Executed a new program.
+    Exec,
+    /// Any other ptrace event.
+    Event(libc::c_int),
+}
+
+impl Stop {
+    /// Signal to deliver when resuming or detaching.
+    fn signal(self) -> libc::c_int {
+        match self {
+            Stop::Signal(signal) => signal,
+            _ => 0,
+        }
+    }
+}
+
+/// State change of a traced thread reported by waitpid(2).
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Status {
+    /// Nothing to report.
+    Running,
+    Stopped(Stop),
+    Exited,
+}
+
+/// Decodes a status from waitpid(2). Clone events are returned with thread ID 0.
+fn decode_status(status: libc::c_int) -> Status {
+    if libc::WIFEXITED(status) || libc::WIFSIGNALED(status) {
+        return Status::Exited;
+    }
+    if !libc::WIFSTOPPED(status) {
+        return Status::Running;
+    }
+    let signal = libc::WSTOPSIG(status);
+    Status::Stopped(match status >> 16 {
+        0 => Stop::Signal(signal),
+        // Interrupts of a stopped process report the stop signal, like the group-stop itself.
+        libc::PTRACE_EVENT_STOP => match signal {
+            libc::SIGSTOP | libc::SIGTSTP | libc::SIGTTIN | libc::SIGTTOU => Stop::Group(signal),
+            _ => Stop::Interrupt,
+        },
+        libc::PTRACE_EVENT_CLONE => Stop::Clone(0),
+        libc::PTRACE_EVENT_EXEC => Stop::Exec,
+        event => Stop::Event(event),
+    })
+}
+
+/// Resumes a thread from a ptrace-stop the way it was stopped.
+fn resume(tid: libc::pid_t, stop: Stop) {
+    // PTRACE_LISTEN lets the thread wait for SIGCONT without leaving the group-stop.
+    let request = if let Stop::Group(_) = stop { libc::PTRACE_LISTEN } else { libc::PTRACE_CONT };
+    unsafe {
+        if libc::ptrace(request, tid, std::ptr::null::<libc::c_void>(), stop.signal() as *const libc::c_void) == -1 {
+            warn!(lwp = tid; "resuming thread {} failed: {}", tid, errno::errno());
+        }
+    }
 }
 
 impl ProcHandle {
-    pub fn new(pid: i32) -> Result<ProcHandle, Box<dyn std::error::Error>> {
-        let handle = ProcHandle { pid, symbols: HashMap::new() };
-        unsafe {
-            // Attach to the process with ptrace, but don't stop it. We need this later on to read
-            // and write data from the process.
-            match libc::ptrace(libc::PTRACE_SEIZE, pid, std::ptr::null() as *const libc::c_void, std::ptr::null() as *const libc::c_void) {
-                -1 => return Err(Box::new(std::io::Error::from(errno::errno()))),
-                _  => () // ok
+    /// Attaches to all threads of the process with ptrace, without stopping them.
+    ///
+    /// Threads created later are attached automatically by the kernel. Their creation is reported
+    /// as an event that `handle_events()` processes.
+    pub fn new(pid: i32) -> Result<ProcHandle, std::io::Error> {
+        // Open the pidfd first, so that it refers to the process we attach to.
+        let pidfd = pidfd_open(pid);
+        seize(pid)?;
+        // Only create the handle now, dropping it detaches.
+        let handle = ProcHandle {
+            pid,
+            symbols: HashMap::new(),
+            ptrace: true,
+            read_only: false,
+            tasks: RefCell::new(std::iter::once(pid).collect()),
+            stopped_lwp: Cell::new(None),
+            last_error: RefCell::new(None),
+            pidfd,
+            state: Cell::new(ProcState::Alive),
+            calls: Cell::new(0),
+            core: None,
+        };
+        handle.check_state()?;
+        handle.seize_tasks()?;
+        Ok(handle)
+    }
+
+    /// Creates a handle that reads memory with process_vm_readv(2) or /proc/<pid>/mem instead of
+    /// attaching with ptrace.
+    pub fn new_without_ptrace(pid: i32) -> Result<ProcHandle, std::io::Error> {
+        let pidfd = pidfd_open(pid);
+        // Fail early for processes that don't exist.
+        std::fs::metadata(format!("/proc/{}", pid))?;
+        let handle = ProcHandle {
+            pid,
+            symbols: HashMap::new(),
+            ptrace: false,
+            read_only: true,
+            tasks: RefCell::new(BTreeSet::new()),
+            stopped_lwp: Cell::new(None),
+            last_error: RefCell::new(None),
+            pidfd,
+            state: Cell::new(ProcState::Alive),
+            calls: Cell::new(0),
+            core: None,
+        };
+        handle.check_state()?;
+        Ok(handle)
+    }
+
+    /// Creates a handle for the process dumped in `core`. Memory and registers are read from the
+    /// core, which never changes, so the handle is read-only and always alive. `tasks()` returns
+    /// the threads in the core.
+    pub fn for_core(core: CoreFile) -> ProcHandle {
+        ProcHandle {
+            pid: core.pid,
+            symbols: HashMap::new(),
+            ptrace: false,
+            read_only: true,
+            tasks: RefCell::new(core.threads.iter().map(|thread| thread.lwp).collect()),
+            stopped_lwp: Cell::new(None),
+            last_error: RefCell::new(None),
+            pidfd: None,
+            state: Cell::new(ProcState::Alive),
+            calls: Cell::new(0),
+            core: Some(core),
+        }
+    }
+
+    /// Returns the core the handle reads from, `None` for live processes.
+    pub fn core(&self) -> Option<&CoreFile> {
+        self.core.as_ref()
+    }
+
+    /// Returns the named mappings of the process, from the core or `/proc/<pid>/maps`.
+    pub fn mappings(&self) -> Result<Vec<procfs::Mapping>, std::io::Error> {
+        match &self.core {
+            Some(core) => Ok(core.mappings.clone()),
+            None => procfs::read_maps(self.pid),
+        }
+    }
+
+    /// Attaches to the threads in /proc/<pid>/task that aren't attached yet.
+    fn seize_tasks(&self) -> Result<(), std::io::Error> {
+        // Threads that existed before we attached to their creator aren't reported, so scan until
+        // there are no new ones.
+        loop {
+            let mut found_new = false;
+            for entry in std::fs::read_dir(format!("/proc/{}/task", self.pid))? {
+                let tid = match entry?.file_name().to_str().and_then(|name| name.parse().ok()) {
+                    Some(tid) => tid,
+                    None => continue,
+                };
+                if !self.tasks.borrow().contains(&tid) {
+                    found_new |= self.seize_task(tid)?;
+                }
+            }
+            if !found_new {
+                return Ok(());
             }
         }
+    }
 
-        Ok(handle)
+    /// Attaches to the thread `tid` of the process. Returns false if it exited in the meantime.
+    fn seize_task(&self, tid: libc::pid_t) -> Result<bool, std::io::Error> {
+        match seize(tid) {
+            Ok(()) => (),
+            Err(ref e) if e.raw_os_error() == Some(libc::ESRCH) => return Ok(false),
+            // The kernel attaches threads created by attached threads itself, which fails seizing
+            // them again. Their creation may not have been processed yet.
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) && procfs::tracer(tid).ok() == Some(unsafe { libc::gettid() }) => {
+                debug!(pid = self.pid, lwp = tid; "thread {} is attached already", tid);
+            }
+            Err(e) => return Err(e),
+        }
+        self.tasks.borrow_mut().insert(tid);
+        Ok(true)
+    }
+
+    /// Returns the IDs of all attached threads.
+    pub fn tasks(&self) -> Vec<libc::pid_t> {
+        self.tasks.borrow().iter().copied().collect()
+    }
+
+    /// Waits for a state change of the attached thread `tid`, or only checks for one if `block`
+    /// is false. Keeps track of created and exited threads.
+    fn wait(&self, tid: libc::pid_t, block: bool) -> Result<Status, std::io::Error> {
+        let flags = if block { libc::__WALL } else { libc::__WALL | libc::WNOHANG };
+        let mut raw_status = 0;
+        let status = loop {
+            match unsafe { libc::waitpid(tid, &mut raw_status, flags) } {
+                0 => return Ok(Status::Running),
+                -1 => match std::io::Error::last_os_error() {
+                    e if e.raw_os_error() == Some(libc::EINTR) => continue,
+                    // The thread exited and was reaped already.
+                    e if e.raw_os_error() == Some(libc::ECHILD) => break Status::Exited,
+                    e => return Err(e),
+                },
+                _ => break decode_status(raw_status),
+            }
+        };
+        match status {
+            Status::Stopped(Stop::Clone(_)) => {
+                let mut new_tid: libc::c_ulong = 0;
+                unsafe {
+                    if libc::ptrace(libc::PTRACE_GETEVENTMSG, tid, std::ptr::null::<libc::c_void>(), &mut new_tid as *mut libc::c_ulong) == -1 {
+                        return Err(std::io::Error::from(errno::errno()));
+                    }
+                }
+                debug!(pid = self.pid, lwp = new_tid; "new thread {}", new_tid);
+                self.tasks.borrow_mut().insert(new_tid as libc::pid_t);
+                Ok(Status::Stopped(Stop::Clone(new_tid as libc::pid_t)))
+            }
+            Status::Stopped(Stop::Exec) => {
+                debug!(pid = self.pid, lwp = tid; "process {} executed a new program", self.pid);
+                self.state.set(ProcState::Exec);
+                Ok(status)
+            }
+            Status::Exited => {
+                debug!(pid = self.pid, lwp = tid; "thread {} exited", tid);
+                self.tasks.borrow_mut().remove(&tid);
+                // The kernel only reports the exit of the leader after all other threads exited.
+                if tid == self.pid {
+                    self.state.set(ProcState::Exited);
+                }
+                Ok(Status::Exited)
+            }
+            status => Ok(status),
+        }
+    }
+
+    /// Processes pending ptrace events without blocking: attaches new threads, forgets exited
+    /// ones and resumes threads stopped by events or signals. Signals are delivered and stopped
+    /// processes stay stopped.
+    ///
+    /// Until their events are processed, threads that create threads or receive signals stay
+    /// stopped. It takes a `waitpid()` per thread, so it runs once per operation on the process
+    /// rather than for each memory access.
+    pub fn handle_events(&self) {
+        if !self.ptrace {
+            return;
+        }
+        let mut pending: Vec<libc::pid_t> = self.tasks();
+        while let Some(tid) = pending.pop() {
+            // Never steal the stop of the thread held by stop_lwp().
+            if self.stopped_lwp.get() == Some(tid) {
+                continue;
+            }
+            match self.wait(tid, false) {
+                Ok(Status::Stopped(stop)) => {
+                    if let Stop::Clone(new_tid) = stop {
+                        // The new thread starts with a stop as well.
+                        pending.push(new_tid);
+                    }
+                    resume(tid, stop);
+                    pending.push(tid);
+                }
+                Ok(_) => (),
+                Err(e) => debug!(pid = self.pid, lwp = tid; "waiting for thread {} failed: {}", tid, e),
+            }
+        }
+    }
+
+    /// Stops the thread `lwp` until the returned guard is dropped, e.g. to read its registers.
+    pub fn stop_lwp(&self, lwp: libc::pid_t) -> Result<LwpStop<'_>, std::io::Error> {
+        if !self.ptrace {
+            return Err(std::io::Error::other("process is not attached with ptrace"));
+        }
+        self.check_state()?;
+        // The thread was created after the last scan and its creation wasn't processed yet.
+        if !self.tasks.borrow().contains(&lwp) && !self.seize_task(lwp)? {
+            return Err(std::io::Error::from_raw_os_error(libc::ESRCH));
+        }
+        let stopper = Stopper::new(self, lwp)?;
+        let previous = self.stopped_lwp.replace(Some(lwp));
+        Ok(LwpStop { stopper, previous })
+    }
+
+    /// Returns whether the process is still the one that was attached.
+    ///
+    /// Exits are detected with the pidfd, or the exit of the main thread without one. Executing a
+    /// new program is only detected with ptrace, when the event is processed.
+    pub fn state(&self) -> ProcState {
+        if self.state.get() == ProcState::Alive && self.pidfd.as_ref().is_some_and(pidfd_exited) {
+            debug!(pid = self.pid; "process {} exited", self.pid);
+            self.state.set(ProcState::Exited);
+        }
+        self.state.get()
+    }
+
+    /// Fails if the process exited or executed a new program, see `state()`.
+    pub fn check_state(&self) -> Result<(), std::io::Error> {
+        match self.state() {
+            ProcState::Alive => Ok(()),
+            ProcState::Exited => Err(std::io::Error::from_raw_os_error(libc::ESRCH)),
+            ProcState::Exec => Err(std::io::Error::other("process executed a new program")),
+        }
+    }
+
+    /// Reads `buf.len()` bytes at `addr` from the process.
+    pub fn read(&self, addr: usize, buf: &mut [u8]) -> Result<(), std::io::Error> {
+        if let Some(core) = &self.core {
+            return core.read(addr, buf);
+        }
+        if !self.ptrace {
+            self.check_state()?;
+            read_memory_without_ptrace(self.pid, addr, buf)?;
+            // Without ptrace, the pid could have been reused during the read.
+            return self.check_state();
+        }
+        // Any stopped thread gives access to the process's memory.
+        let (tid, _stopper) = match self.stopped_lwp.get() {
+            Some(lwp) => (lwp, None),
+            None => {
+                self.check_state()?;
+                (self.pid, Some(Stopper::new(self, self.pid)?))
+            }
+        };
+        read_memory(tid, addr, buf)
+    }
+
+    /// Writes `buf` at `addr` into the process.
+    pub fn write(&self, addr: usize, buf: &[u8]) -> Result<(), std::io::Error> {
+        if self.read_only {
+            warn!(pid = self.pid, address = addr, size = buf.len(); "refusing to write to read-only process {}", self.pid);
+            return Err(read_only_error());
+        }
+        let (tid, _stopper) = match self.stopped_lwp.get() {
+            Some(lwp) => (lwp, None),
+            None => {
+                self.check_state()?;
+                (self.pid, Some(Stopper::new(self, self.pid)?))
+            }
+        };
+        write_memory(tid, addr, buf)
+    }
+
+    /// Detaches from all threads. Pending signals are delivered and stopped processes stay
+    /// stopped.
+    ///
+    /// Tries every thread even if detaching from one fails, and returns the first error.
+    pub fn detach(&self) -> Result<(), std::io::Error> {
+        if !self.ptrace {
+            return Ok(());
+        }
+        self.handle_events();
+        let mut result = Ok(());
+        // Threads may still be created while detaching, so take one at a time.
+        loop {
+            let next = self.tasks.borrow().iter().next().copied();
+            let tid = match next {
+                Some(tid) => tid,
+                None => break,
+            };
+            // PTRACE_DETACH only works on stopped tracees.
+            let detached = match Stopper::new(self, tid) {
+                Ok(stopper) => stopper.detach(),
+                // Exited threads are detached already.
+                Err(ref e) if e.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = detached {
+                debug!(pid = self.pid, lwp = tid; "detaching thread {} failed: {}", tid, e);
+                result = result.and(Err(e));
+            }
+            self.tasks.borrow_mut().remove(&tid);
+        }
+        result
+    }
+
+    /// Forgets the error of the last failed callback.
+    pub fn clear_error(&self) {
+        self.last_error.borrow_mut().take();
+    }
+
+    /// Returns the error of the last failed callback since `clear_error()`.
+    pub fn take_error(&self) -> Option<CallbackError> {
+        self.last_error.borrow_mut().take()
+    }
+
+    /// Marks a libthread_db call on the process as in progress until the returned guard is
+    /// dropped. Calls can nest, e.g. when a `td_ta_thr_iter` callback makes calls.
+    pub fn enter_call(&self) -> Call<'_> {
+        self.calls.set(self.calls.get() + 1);
+        Call { handle: self }
+    }
+
+    /// Whether a libthread_db call is in progress, see `enter_call()`.
+    pub fn in_call(&self) -> bool {
+        self.calls.get() > 0
+    }
+}
+
+/// A libthread_db call in progress, see `ProcHandle::enter_call()`.
+pub struct Call<'h> {
+    handle: &'h ProcHandle,
+}
+
+impl Drop for Call<'_> {
+    fn drop(&mut self) {
+        self.handle.calls.set(self.handle.calls.get() - 1);
+    }
+}
+
+/// Returns whether the process of the pidfd exited.
+fn pidfd_exited(pidfd: &OwnedFd) -> bool {
+    // pidfds become readable when the process exits.
+    let mut pollfd = libc::pollfd { fd: pidfd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    unsafe { libc::poll(&mut pollfd, 1, 0) == 1 }
+}
+
+fn read_only_error() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::PermissionDenied, "process is attached read-only")
+}
+
+/// A thread stopped by `ProcHandle::stop_lwp()`, resumed on drop.
+pub struct LwpStop<'h> {
+    stopper: Stopper<'h>,
+    /// Thread that was stopped before, for nested stops.
+    previous: Option<libc::pid_t>,
+}
+
+impl Drop for LwpStop<'_> {
+    fn drop(&mut self) {
+        // The stopper resumes the thread afterwards.
+        self.stopper.handle.stopped_lwp.set(self.previous);
     }
 }
 
 impl Drop for ProcHandle {
     fn drop(&mut self) {
-        unsafe {
-            match libc::ptrace(libc::PTRACE_DETACH, self.pid, std::ptr::null() as *const libc::c_void, std::ptr::null() as *const libc::c_void) {
-                -1 => eprintln!("Detaching process with pid {} failed: {:?}", self.pid, errno::errno()),
-                _ => (),
-            }
+        if let Err(e) = self.detach() {
+            warn!(pid = self.pid; "detaching from process {} failed: {}", self.pid, e);
         }
     }
 }
@@ -76,51 +565,131 @@ impl std::fmt::Debug for ProcHandle {
     }
 }
 
-/// Automatically resumes the ptrace-stopped process on drop.
-struct Stopper {
-    pid: i32,
+/// An attached thread in a ptrace-stop. Resumes it on drop the way it was stopped.
+struct Stopper<'h> {
+    handle: &'h ProcHandle,
+    tid: libc::pid_t,
+    stop: Stop,
 }
 
-impl Stopper {
-    /// Stops the process.
-    fn new(pid: i32) -> Result<Stopper, Box<dyn std::error::Error>> {
+impl Stopper<'_> {
+    /// Stops the thread `tid`, which must be attached to `handle`. Fails with ESRCH if it exited.
+    fn new(handle: &ProcHandle, tid: libc::pid_t) -> Result<Stopper<'_>, std::io::Error> {
         unsafe {
-            if libc::ptrace(libc::PTRACE_INTERRUPT, pid, std::ptr::null() as *const libc::c_void, std::ptr::null() as *const libc::c_void) == -1 {
-                return Err(Box::new(std::io::Error::from(errno::errno())));
+            if libc::ptrace(libc::PTRACE_INTERRUPT, tid, std::ptr::null::<libc::c_void>(), std::ptr::null::<libc::c_void>()) == -1 {
+                return Err(std::io::Error::from(errno::errno()));
             }
         }
-        match nix::sys::wait::waitpid(Some(nix::unistd::Pid::from_raw(pid)), Some(nix::sys::wait::WaitPidFlag::__WALL)) {
-            Err(e) => return Err(Box::new(e)),
-            Ok(_) => (), // TODO: Not all non-error states indicate a stopped process.
+        // Any ptrace-stop will do, the thread may stop for a signal or an event before the
+        // interrupt. In that case the interrupt stops it again later, which handle_events()
+        // takes care of.
+        loop {
+            match handle.wait(tid, true)? {
+                Status::Stopped(stop) => return Ok(Stopper { handle, tid, stop }),
+                Status::Exited => return Err(std::io::Error::from_raw_os_error(libc::ESRCH)),
+                Status::Running => (),
+            }
         }
-        Ok(Stopper { pid })
     }
-}
 
-impl Drop for Stopper {
-    fn drop(&mut self) {
+    /// Detaches from the thread, delivering a pending signal. Stopped processes stay stopped.
+    fn detach(self) -> Result<(), std::io::Error> {
         unsafe {
-            libc::ptrace(libc::PTRACE_CONT, self.pid, std::ptr::null() as *const libc::c_void, std::ptr::null() as *const libc::c_void);
+            if libc::ptrace(libc::PTRACE_DETACH, self.tid, std::ptr::null::<libc::c_void>(), self.stop.signal() as *const libc::c_void) == -1 {
+                // Resume the thread on drop instead.
+                return Err(std::io::Error::from(errno::errno()));
+            }
         }
+        // Detaching resumed the thread already.
+        std::mem::forget(self);
+        Ok(())
+    }
+}
+
+impl Drop for Stopper<'_> {
+    fn drop(&mut self) {
+        resume(self.tid, self.stop);
     }
 }
 
+/// Returns the names and addresses of the callbacks libthread_db imports.
+pub fn callbacks() -> Vec<(&'static str, usize)> {
+    vec![
+        ("ps_getpid", ps_getpid as *const () as usize),
+        ("ps_pdread", ps_pdread as *const () as usize),
+        ("ps_pdwrite", ps_pdwrite as *const () as usize),
+        ("ps_lgetregs", ps_lgetregs as *const () as usize),
+        ("ps_lsetregs", ps_lsetregs as *const () as usize),
+        ("ps_lgetfpregs", ps_lgetfpregs as *const () as usize),
+        ("ps_lsetfpregs", ps_lsetfpregs as *const () as usize),
+        ("ps_pglobal_lookup", ps_pglobal_lookup as *const () as usize),
+    ]
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn ps_getpid(handle: *mut ProcHandle) -> i32 {
-    ps_trace!("ps_getpid({:?})", *handle);
+    ps_trace!(pid = (*handle).pid; "ps_getpid");
     (*handle).pid
 }
 
+/// Result for libthread_db and the underlying error of a failed callback.
+type Failure = (PsErr, std::io::Error);
+
+/// Maps an error accessing the process to the result for libthread_db. `gone` is returned if the
+/// process or thread doesn't exist (anymore).
+fn failure(error: std::io::Error, gone: PsErr) -> Failure {
+    let result = match error.raw_os_error() {
+        Some(libc::ESRCH) => gone,
+        // ptrace and /proc/<pid>/mem fail with EIO, process_vm_readv with EFAULT.
+        Some(libc::EIO) | Some(libc::EFAULT) => PsErr::BadAddr,
+        _ => PsErr::Err,
+    };
+    (result, error)
+}
+
+/// Runs the body of the callback `name`. Errors and panics are returned to libthread_db as
+/// `PsErr` and recorded on the handle, see `ProcHandle::take_error()`.
+unsafe fn run_callback<F>(handle: *mut ProcHandle, name: &'static str, f: F) -> PsErr
+    where F: FnOnce(&ProcHandle) -> Result<(), Failure>
+{
+    let handle = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return PsErr::BadPID,
+    };
+    // Don't unwind into libthread_db.
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| f(handle))).unwrap_or_else(|panic| {
+        let msg = panic.downcast_ref::<&str>().copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        Err((PsErr::Err, std::io::Error::other(format!("panicked: {}", msg))))
+    });
+    match result {
+        Ok(()) => PsErr::Ok,
+        Err((result, error)) => {
+            debug!(pid = handle.pid; "{} failed: {}", name, error);
+            if let Ok(mut last_error) = handle.last_error.try_borrow_mut() {
+                *last_error = Some(CallbackError { callback: name, error });
+            }
+            result
+        }
+    }
+}
+
+/// Returns the pid of the handle for logging, or -1 for a null handle.
+unsafe fn pid(handle: *const ProcHandle) -> libc::pid_t {
+    handle.as_ref().map_or(-1, |handle| handle.pid)
+}
+
 /// Reads one word at addr from pid.
 /// Assumes that the process is already stopped.
-unsafe fn read_data(pid: libc::pid_t, addr: *mut PsAddr) -> Result<usize, PsErr> {
+unsafe fn read_data(pid: libc::pid_t, addr: *mut PsAddr) -> Result<usize, std::io::Error> {
     set_errno(Errno(0));
-    let result = libc::ptrace(libc::PTRACE_PEEKDATA, pid, addr, std::ptr::null_mut() as *mut libc::c_void);
+    let result = libc::ptrace(libc::PTRACE_PEEKDATA, pid, addr, std::ptr::null_mut::<libc::c_void>());
     match (result, errno()) {
         (-1, Errno(0)) => Ok(result as usize),
         (-1, e) => {
-            eprintln!("read_data({:?}, {:?}): {:?}", pid, addr, e);
-            Err(PsErr::Err)
+            debug!(pid = pid, address:? = addr; "PTRACE_PEEKDATA failed: {}", e);
+            Err(std::io::Error::from(e))
         },
         _ => Ok(result as usize),
     }
@@ -128,137 +697,315 @@ unsafe fn read_data(pid: libc::pid_t, addr: *mut PsAddr) -> Result<usize, PsErr>
 
 /// Writes one word at addr in process <pid>'s address space.
 /// Assumes that the process is already stopped.
-unsafe fn write_data(pid: libc::pid_t, addr: *mut PsAddr, data: libc::uintptr_t) -> Result<(), PsErr> {
+unsafe fn write_data(pid: libc::pid_t, addr: *mut PsAddr, data: libc::uintptr_t) -> Result<(), std::io::Error> {
     match libc::ptrace(libc::PTRACE_POKEDATA, pid, addr, data) {
-        -1 => Err(PsErr::Err),
+        -1 => Err(std::io::Error::from(errno())),
         _ => Ok(()),
     }
 }
 
+/// Reads memory of a running process with process_vm_readv(2), falling back to /proc/<pid>/mem
+/// where the system call isn't available.
+fn read_memory_without_ptrace(pid: libc::pid_t, addr: usize, buf: &mut [u8]) -> Result<(), std::io::Error> {
+    let local = libc::iovec { iov_base: buf.as_mut_ptr() as *mut libc::c_void, iov_len: buf.len() };
+    let remote = libc::iovec { iov_base: addr as *mut libc::c_void, iov_len: buf.len() };
+    let read = unsafe { libc::process_vm_readv(pid, &local, 1, &remote, 1, 0) };
+    if read == buf.len() as isize {
+        return Ok(());
+    }
+    debug!(pid = pid, address = addr, size = buf.len(); "process_vm_readv failed ({}), reading /proc/{}/mem", if read == -1 { errno().to_string() } else { format!("read {} bytes", read) }, pid);
+    let result = std::fs::File::open(format!("/proc/{}/mem", pid))
+        .and_then(|mem| mem.read_exact_at(buf, addr as u64));
+    if let Err(ref e) = result {
+        debug!(pid = pid, address = addr, size = buf.len(); "reading /proc/{}/mem failed: {}", pid, e);
+    }
+    result
+}
+
 #[no_mangle]
 pub unsafe extern "C" fn ps_pdread(handle: *mut ProcHandle, ps_addr: *mut PsAddr, addr: *mut libc::c_void, size: usize) -> PsErr {
-    ps_trace!("ps_pdread({:?}, {:?}, {:?}, {})", *handle, ps_addr, addr, size);
-    let pid = (*handle).pid;
-    let _stopper = Stopper::new(pid).expect("could not stop process");
-    let mut source_ptr = ps_addr as *mut usize;
-    let mut target_ptr = addr as *mut usize;
-    let step = std::mem::size_of::<usize>();
-    let mut size = size;
-    loop {
-        match read_data(pid, source_ptr as *mut PsAddr) {
-            Err(e) => { return e; },
-            Ok(data) => {
-                if size > step {
-                    *target_ptr = data;
-                } else {
-                    // Last partial read
-                    std::ptr::copy_nonoverlapping(&data as *const _ as *const u8, target_ptr as *mut u8, size);
-                    break;
-                }
-            },
+    let result = run_callback(handle, "ps_pdread", |handle| {
+        handle.read(ps_addr as usize, std::slice::from_raw_parts_mut(addr as *mut u8, size))
+            .map_err(|e| failure(e, PsErr::BadPID))
+    });
+    ps_trace!(pid = pid(handle), address:? = ps_addr, size = size, result:? = result; "ps_pdread");
+    result
+}
+
+/// Size of the words read and written with ptrace.
+const WORD_SIZE: usize = std::mem::size_of::<usize>();
+
+/// Splits the memory range of `len` bytes at `addr` into chunks within aligned words. Returns the
+/// address of each word, the offset into the word and the offset into the range of each chunk.
+///
+/// Aligned words never cross page boundaries, so accessing them doesn't fail as long as the range
+/// itself is mapped.
+fn word_chunks(addr: usize, len: usize) -> impl Iterator<Item = (usize, std::ops::Range<usize>, usize)> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        if offset == len {
+            return None;
         }
-        target_ptr = target_ptr.add(1);
-        source_ptr = source_ptr.add(1);
-        size -= step;
+        let current = addr + offset;
+        let word = current - current % WORD_SIZE;
+        let start = current - word;
+        let end = WORD_SIZE.min(start + len - offset);
+        let chunk = (word, start..end, offset);
+        offset += end - start;
+        Some(chunk)
+    })
+}
+
+/// Reads `buf.len()` bytes at `addr` from the stopped thread `pid`.
+fn read_memory(pid: libc::pid_t, addr: usize, buf: &mut [u8]) -> Result<(), std::io::Error> {
+    for (word, range, offset) in word_chunks(addr, buf.len()) {
+        let data = unsafe { read_data(pid, word as *mut PsAddr)? }.to_ne_bytes();
+        buf[offset..offset + range.len()].copy_from_slice(&data[range]);
     }
-    PsErr::Ok
+    Ok(())
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn ps_pdwrite(handle: *mut ProcHandle, ps_addr: *mut PsAddr, addr: *const libc::c_void, size: usize) -> PsErr {
-    ps_trace!("ps_pdwrite({:?}, {:?}, {:?}, {})", *handle, ps_addr, addr, size);
-    let pid = (*handle).pid;
-    let _stopper = Stopper::new(pid).expect("could not stop process");
-    let mut target_ptr = ps_addr as *mut usize;
-    let mut source_ptr = addr as *mut usize;
-    let step = std::mem::size_of::<usize>();
-    let mut size = size;
-    loop {
-        if size >= step {
-            if let Err(e) = write_data(pid, target_ptr as *mut PsAddr, *source_ptr) {
-                return e;
-            }
-            if size == step {
-                break;
-            }
+    let result = run_callback(handle, "ps_pdwrite", |handle| {
+        handle.write(ps_addr as usize, std::slice::from_raw_parts(addr as *const u8, size))
+            .map_err(|e| failure(e, PsErr::BadPID))
+    });
+    ps_trace!(pid = pid(handle), address:? = ps_addr, size = size, result:? = result; "ps_pdwrite");
+    result
+}
+
+/// Writes `buf` to `addr` in the stopped thread `pid`.
+fn write_memory(pid: libc::pid_t, addr: usize, buf: &[u8]) -> Result<(), std::io::Error> {
+    for (word, range, offset) in word_chunks(addr, buf.len()) {
+        let mut data = if range.len() == WORD_SIZE {
+            [0; WORD_SIZE]
         } else {
-            // read-modify-write necessary to write the remaining bytes.
-            match read_data(pid, target_ptr as *mut PsAddr) {
-                Err(e) => { return e; }
-                Ok(word) => {
-                    let mut new_word = word;
-                    std::ptr::copy_nonoverlapping(source_ptr as *const u8, &mut new_word as *mut _ as *mut u8, size);
-                    if let Err(e) = write_data(pid, target_ptr as *mut PsAddr, new_word) {
-                        return e;
-                    }
-                },
-            }
-            break;
-        }
-        target_ptr = target_ptr.add(1);
-        source_ptr = source_ptr.add(1);
-        size -= step;
+            // Keep the bytes of the word outside of the range.
+            unsafe { read_data(pid, word as *mut PsAddr)? }.to_ne_bytes()
+        };
+        data[range.clone()].copy_from_slice(&buf[offset..offset + range.len()]);
+        unsafe { write_data(pid, word as *mut PsAddr, usize::from_ne_bytes(data))? };
+    }
+    Ok(())
+}
+
+/// Runs a ptrace register request on thread `lwpid`, which has to be stopped for it.
+unsafe fn register_request(handle: &ProcHandle, request: libc::c_uint, lwpid: libc::pid_t, registers: *mut libc::c_void) -> Result<(), Failure> {
+    if let Some(core) = &handle.core {
+        return core_registers(core, request, lwpid, registers);
+    }
+    // Registers can only be accessed with ptrace.
+    if !handle.ptrace {
+        return Err((PsErr::Err, std::io::Error::other("process is not attached with ptrace")));
+    }
+    let _stop = if handle.stopped_lwp.get() == Some(lwpid) {
+        None
+    } else {
+        Some(handle.stop_lwp(lwpid).map_err(|e| failure(e, PsErr::BadLID))?)
+    };
+    match libc::ptrace(request, lwpid, 0, registers) {
+        -1 => Err(failure(std::io::Error::from(errno()), PsErr::BadLID)),
+        _ => Ok(()),
     }
-    PsErr::Ok
+}
+
+/// Answers a ptrace register request on thread `lwpid` from the registers saved in the core.
+unsafe fn core_registers(core: &CoreFile, request: libc::c_uint, lwpid: libc::pid_t, registers: *mut libc::c_void) -> Result<(), Failure> {
+    let thread = match core.thread(lwpid) {
+        Some(thread) => thread,
+        None => return Err(failure(std::io::Error::from_raw_os_error(libc::ESRCH), PsErr::BadLID)),
+    };
+    match request {
+        libc::PTRACE_GETREGS => *(registers as *mut libc::user_regs_struct) = thread.regs,
+        libc::PTRACE_GETFPREGS => match thread.fpregs {
+            Some(fpregs) => *(registers as *mut libc::user_fpregs_struct) = fpregs,
+            None => return Err((PsErr::NoFRegs, std::io::Error::other(format!("core has no floating point registers of thread {}", lwpid)))),
+        },
+        // Cores are read-only.
+        _ => return Err((PsErr::Err, read_only_error())),
+    }
+    Ok(())
+}
+
+/// Fails for handles of processes attached read-only.
+fn check_writable(handle: &ProcHandle, lwpid: libc::pid_t) -> Result<(), Failure> {
+    if handle.read_only {
+        warn!(pid = handle.pid, lwp = lwpid; "refusing to set registers of read-only process {}", handle.pid);
+        return Err((PsErr::Err, read_only_error()));
+    }
+    Ok(())
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn ps_lgetregs(handle: *mut ProcHandle, lwpid: libc::pid_t, registers: *mut libc::c_void) -> PsErr {
-    ps_trace!("ps_lgetregs({:?}, {}, {:?})", *handle, lwpid, registers);
-    match libc::ptrace(libc::PTRACE_GETREGS, lwpid, 0, registers) {
-        -1 => PsErr::Err,
-        _ => PsErr::Ok,
-    }
+    let result = run_callback(handle, "ps_lgetregs", |handle| {
+        register_request(handle, libc::PTRACE_GETREGS, lwpid, registers)
+    });
+    ps_trace!(pid = pid(handle), lwp = lwpid, result:? = result; "ps_lgetregs");
+    result
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn ps_lsetregs(handle: *mut ProcHandle, lwpid: libc::pid_t, registers: *mut libc::c_void) -> PsErr {
-    ps_trace!("ps_lsetregs({:?}, {}, {:?})", *handle, lwpid, registers);
-    match libc::ptrace(libc::PTRACE_SETREGS, lwpid, 0, registers) {
-        -1 => PsErr::Err,
-        _ => PsErr::Ok,
-    }
+    let result = run_callback(handle, "ps_lsetregs", |handle| {
+        check_writable(handle, lwpid)?;
+        register_request(handle, libc::PTRACE_SETREGS, lwpid, registers)
+    });
+    ps_trace!(pid = pid(handle), lwp = lwpid, result:? = result; "ps_lsetregs");
+    result
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn ps_lgetfpregs(handle: *mut ProcHandle, lwpid: libc::pid_t, registers: *mut libc::c_void) -> PsErr {
-    ps_trace!("ps_lgetfpregs({:?}, {}, {:?})", *handle, lwpid, registers);
-    match libc::ptrace(libc::PTRACE_GETFPREGS, lwpid, 0, registers) {
-        -1 => PsErr::Err,
-        _ => PsErr::Ok,
-    }
+    let result = run_callback(handle, "ps_lgetfpregs", |handle| {
+        register_request(handle, libc::PTRACE_GETFPREGS, lwpid, registers)
+    });
+    ps_trace!(pid = pid(handle), lwp = lwpid, result:? = result; "ps_lgetfpregs");
+    result
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn ps_lsetfpregs(handle: *mut ProcHandle, lwpid: libc::pid_t, registers: *mut libc::c_void) -> PsErr {
-    ps_trace!("ps_lsetfpregs({:?}, {}, {:?})", *handle, lwpid, registers);
-    match libc::ptrace(libc::PTRACE_SETFPREGS, lwpid, 0, registers) {
-        -1 => PsErr::Err,
-        _ => PsErr::Ok,
+    let result = run_callback(handle, "ps_lsetfpregs", |handle| {
+        check_writable(handle, lwpid)?;
+        register_request(handle, libc::PTRACE_SETFPREGS, lwpid, registers)
+    });
+    ps_trace!(pid = pid(handle), lwp = lwpid, result:? = result; "ps_lsetfpregs");
+    result
+}
+
+/// Returns a C string from libthread_db for logging.
+unsafe fn c_str<'a>(s: *const libc::c_char) -> std::borrow::Cow<'a, str> {
+    if s.is_null() {
+        "".into()
+    } else {
+        CStr::from_ptr(s).to_string_lossy()
     }
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn ps_pglobal_lookup(handle: *mut ProcHandle, object_name: *const libc::c_char, sym_name: *const libc::c_char, sym_addr: *mut *mut PsAddr) -> PsErr {
-    let object_name = CStr::from_ptr(object_name).to_str().unwrap();
-    let sym_name = CStr::from_ptr(sym_name).to_str().unwrap();
-    ps_trace!("ps_pglobal_lookup({:?}, {:?}, {:?}, {:?})", *handle, object_name, sym_name, sym_addr);
-
-    if (*handle).symbols.contains_key(sym_name) {
-        *sym_addr = (*handle).symbols[sym_name] as *mut PsAddr;
-        ps_trace!(" -> {} :: {} = {:?}", object_name, sym_name, *sym_addr);
-        PsErr::Ok
-    } else {
-        PsErr::NoSym
-    }
+    let mut address = None;
+    let result = run_callback(handle, "ps_pglobal_lookup", |handle| {
+        // Symbol names that aren't UTF-8 can't be in the table.
+        address = Some(sym_name).filter(|name| !name.is_null())
+            .and_then(|name| CStr::from_ptr(name).to_str().ok())
+            .and_then(|name| handle.symbols.get(name).copied());
+        match address {
+            Some(address) => {
+                *sym_addr = address as *mut PsAddr;
+                Ok(())
+            },
+            None => {
+                let msg = format!("symbol {} not found", c_str(sym_name));
+                Err((PsErr::NoSym, std::io::Error::new(std::io::ErrorKind::NotFound, msg)))
+            },
+        }
+    });
+    ps_trace!(pid = pid(handle), object = c_str(object_name).as_ref(), symbol = c_str(sym_name).as_ref(), address:? = address, result:? = result; "ps_pglobal_lookup");
+    result
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::mem::size_of;
+    use std::sync::Mutex;
     use libc::c_void;
 
+    /// Records the message and key-value pairs of all proc_service log records.
+    struct TestLogger {
+        records: Mutex<Vec<(String, HashMap<String, String>)>>,
+    }
+
+    impl log::Log for TestLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            struct Collect(HashMap<String, String>);
+            impl<'kvs> log::kv::VisitSource<'kvs> for Collect {
+                fn visit_pair(&mut self, key: log::kv::Key<'kvs>, value: log::kv::Value<'kvs>) -> Result<(), log::kv::Error> {
+                    self.0.insert(key.to_string(), value.to_string());
+                    Ok(())
+                }
+            }
+            if record.target() == "libthread_db::proc_service" {
+                let mut kv = Collect(HashMap::new());
+                record.key_values().visit(&mut kv).unwrap();
+                self.records.lock().unwrap().push((record.args().to_string(), kv.0));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: TestLogger = TestLogger { records: Mutex::new(Vec::new()) };
+
+    /// Returns a handle for `pid` without attaching. It has no threads, so dropping it does
+    /// nothing.
+    fn fake(pid: i32, read_only: bool) -> ProcHandle {
+        ProcHandle { pid, symbols: HashMap::new(), ptrace: true, read_only, tasks: RefCell::new(BTreeSet::new()), stopped_lwp: Cell::new(None), last_error: RefCell::new(None), pidfd: None, state: Cell::new(ProcState::Alive), calls: Cell::new(0), core: None }
+    }
+
+    #[test]
+    fn trace_calls_works() {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+        // Use a pid that can't exist so that the handle is distinguishable from other tests'.
+        let mut handle = fake(-4242, false);
+        let is_ours = |(msg, kv): &(String, HashMap<String, String>)| msg == "ps_getpid" && kv.get("pid").map(String::as_str) == Some("-4242");
+
+        unsafe { ps_getpid(&mut handle); }
+        assert!(!LOGGER.records.lock().unwrap().iter().any(is_ours), "traced call while disabled");
+
+        set_trace_calls(true);
+        unsafe { ps_getpid(&mut handle); }
+        set_trace_calls(false);
+        assert!(LOGGER.records.lock().unwrap().iter().any(is_ours), "call was not traced");
+    }
+
+    #[test]
+    fn read_only_refuses_writes() {
+        // Writing would fail when trying to stop the nonexistent process.
+        let mut handle = fake(-4243, true);
+        let mut value = 0u64;
+        let mut registers: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+        unsafe {
+            assert_eq!(ps_pdwrite(&mut handle, &mut value as *mut _ as *mut c_void, &value as *const _ as *const c_void, size_of::<u64>()), PsErr::Err);
+            assert_eq!(ps_lsetregs(&mut handle, -4243, &mut registers as *mut _ as *mut c_void), PsErr::Err);
+            assert_eq!(ps_lsetfpregs(&mut handle, -4243, &mut registers as *mut _ as *mut c_void), PsErr::Err);
+        }
+    }
+
+    #[test]
+    fn callback_errors_are_recorded() {
+        let mut handle = fake(-4244, false);
+        let mut value = 0u64;
+        let mut registers: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+        let mut address = std::ptr::null_mut();
+        unsafe {
+            assert_eq!(ps_pdread(std::ptr::null_mut(), &mut value as *mut _ as *mut c_void, &mut value as *mut _ as *mut c_void, size_of::<u64>()), PsErr::BadPID);
+
+            assert_eq!(ps_pdread(&mut handle, &mut value as *mut _ as *mut c_void, &mut value as *mut _ as *mut c_void, size_of::<u64>()), PsErr::BadPID);
+            let err = handle.take_error().expect("no error recorded");
+            assert_eq!((err.callback, err.error.raw_os_error()), ("ps_pdread", Some(libc::ESRCH)));
+            assert!(handle.take_error().is_none());
+
+            assert_eq!(ps_lgetregs(&mut handle, -4244, &mut registers as *mut _ as *mut c_void), PsErr::BadLID);
+            assert_eq!(handle.take_error().unwrap().callback, "ps_lgetregs");
+
+            // Symbol names that aren't UTF-8 used to panic.
+            let object = CStr::from_bytes_with_nul(b"libc.so.6\0").unwrap();
+            let symbol = CStr::from_bytes_with_nul(b"nptl_\xff\0").unwrap();
+            assert_eq!(ps_pglobal_lookup(&mut handle, object.as_ptr(), symbol.as_ptr(), &mut address), PsErr::NoSym);
+            let err = handle.take_error().unwrap();
+            assert_eq!((err.callback, err.error.kind()), ("ps_pglobal_lookup", std::io::ErrorKind::NotFound));
+        }
+    }
+
     #[test]
     fn ps_pdread_works() {
         let mut u64_value = 0x1122334455667788u64;
@@ -346,4 +1093,99 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn word_chunks_work() {
+        let chunks: Vec<_> = word_chunks(0x1005, 13).collect();
+        assert_eq!(chunks, vec![(0x1000, 5..8, 0), (0x1008, 0..8, 3), (0x1010, 0..2, 11)]);
+        let chunks: Vec<_> = word_chunks(0x1002, 3).collect();
+        assert_eq!(chunks, vec![(0x1000, 2..5, 0)]);
+        assert_eq!(word_chunks(0x1000, 0).count(), 0);
+    }
+
+    #[test]
+    fn page_edges_work() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let pages = unsafe {
+            libc::mmap(std::ptr::null_mut(), 3 * page_size, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0)
+        };
+        assert_ne!(pages, libc::MAP_FAILED);
+        let page = pages as usize + page_size;
+        for i in 0..page_size {
+            unsafe { *((page + i) as *mut u8) = i as u8; }
+        }
+        let end = page + page_size;
+        let mut ready = [0; 2];
+        assert_eq!(unsafe { libc::pipe(ready.as_mut_ptr()) }, 0);
+
+        match unsafe { libc::fork() } {
+            -1 => panic!("fork failed: {:?}", errno::errno()),
+            0 => { // child
+                // Only leave the middle page mapped. ptrace can read and write PROT_NONE pages, so
+                // protecting them isn't enough.
+                unsafe {
+                    libc::munmap(pages, page_size);
+                    libc::munmap(end as *mut c_void, page_size);
+                    libc::write(ready[1], b"x".as_ptr() as *const c_void, 1);
+                }
+                std::thread::sleep(std::time::Duration::from_millis(2000));
+                std::process::exit(0);
+            },
+            pid => { // parent
+                let mut byte = 0u8;
+                assert_eq!(unsafe { libc::read(ready[0], &mut byte as *mut u8 as *mut c_void, 1) }, 1);
+                let mut handle = ProcHandle::new(pid).expect("creating ProcHandle failed");
+                let read = |handle: &mut ProcHandle, addr: usize, len: usize| {
+                    let mut buf = vec![0u8; len];
+                    let result = unsafe { ps_pdread(handle, addr as *mut PsAddr, buf.as_mut_ptr() as *mut c_void, len) };
+                    (result, buf)
+                };
+                let expected = |addr: usize, len: usize| (addr - page..addr - page + len).map(|i| i as u8).collect::<Vec<u8>>();
+
+                // Unaligned and partial words ending at the end of the page.
+                for &(addr, len) in &[(end - 3, 3), (end - 13, 13), (end - 8, 8), (page, page_size), (page + 1, 2)] {
+                    assert_eq!(read(&mut handle, addr, len), (PsErr::Ok, expected(addr, len)), "reading {:#x}+{}", addr, len);
+                }
+                assert_eq!(read(&mut handle, end - 3, 4).0, PsErr::BadAddr);
+                assert_eq!(read(&mut handle, page - 1, 2).0, PsErr::BadAddr);
+
+                unsafe {
+                    let data = [0xaau8, 0xbb, 0xcc];
+                    assert_eq!(ps_pdwrite(&mut handle, (end - 3) as *mut PsAddr, data.as_ptr() as *const c_void, 3), PsErr::Ok);
+                    assert_eq!(ps_pdwrite(&mut handle, (page + 6) as *mut PsAddr, data.as_ptr() as *const c_void, 3), PsErr::Ok);
+                }
+                let mut tail = expected(end - 5, 2);
+                tail.extend_from_slice(&[0xaa, 0xbb, 0xcc]);
+                assert_eq!(read(&mut handle, end - 5, 5), (PsErr::Ok, tail));
+                assert_eq!(read(&mut handle, page + 5, 5), (PsErr::Ok, vec![5, 0xaa, 0xbb, 0xcc, 9]));
+                // Our own copy is unchanged.
+                assert_eq!(unsafe { *((end - 1) as *const u8) }, (page_size - 1) as u8);
+                let data = [0xaau8, 0xbb];
+                assert_eq!(unsafe { ps_pdwrite(&mut handle, (end - 1) as *mut PsAddr, data.as_ptr() as *const c_void, 2) }, PsErr::BadAddr);
+
+                drop(handle);
+                unsafe {
+                    libc::kill(pid, libc::SIGTERM);
+                    libc::close(ready[0]);
+                    libc::close(ready[1]);
+                    libc::munmap(pages, 3 * page_size);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode_status_works() {
+        let stopped = |signal: libc::c_int, event: libc::c_int| event << 16 | signal << 8 | 0x7f;
+        assert_eq!(decode_status(stopped(libc::SIGUSR1, 0)), Status::Stopped(Stop::Signal(libc::SIGUSR1)));
+        assert_eq!(decode_status(stopped(libc::SIGTRAP, libc::PTRACE_EVENT_STOP)), Status::Stopped(Stop::Interrupt));
+        assert_eq!(decode_status(stopped(libc::SIGTSTP, libc::PTRACE_EVENT_STOP)), Status::Stopped(Stop::Group(libc::SIGTSTP)));
+        assert_eq!(decode_status(stopped(libc::SIGTRAP, libc::PTRACE_EVENT_CLONE)), Status::Stopped(Stop::Clone(0)));
+        assert_eq!(decode_status(stopped(libc::SIGTRAP, libc::PTRACE_EVENT_EXEC)), Status::Stopped(Stop::Exec));
+        assert_eq!(decode_status(stopped(libc::SIGTRAP, libc::PTRACE_EVENT_FORK)), Status::Stopped(Stop::Event(libc::PTRACE_EVENT_FORK)));
+        // Exit code 3, killed by SIGKILL, continued.
+        assert_eq!(decode_status(3 << 8), Status::Exited);
+        assert_eq!(decode_status(libc::SIGKILL), Status::Exited);
+        assert_eq!(decode_status(0xffff), Status::Running);
+    }
 }
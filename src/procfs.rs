@@ -0,0 +1,288 @@
+//! Per-thread scheduling information from /proc.
+//!
+//! See proc(5) for the format of the `stat` and `status` files.
+
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::time::Duration;
+
+use crate::error::AttachCause;
+
+/// Kernel scheduling state of a thread, field 3 of `/proc/<pid>/task/<tid>/stat`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum TaskState {
+    /// R: running or runnable.
+    Running,
+    /// S: interruptible sleep.
+    Sleeping,
+    /// D: uninterruptible sleep, usually waiting for disk I/O.
+    DiskSleep,
+    /// T: stopped by a job control signal.
+    Stopped,
+    /// t: stopped by a debugger.
+    TracingStop,
+    /// Z: exited, but not yet reaped.
+    Zombie,
+    /// X: dead.
+    Dead,
+    /// I: idle kernel thread.
+    Idle,
+    /// Any other state character.
+    Other(char),
+}
+
+impl From<char> for TaskState {
+    fn from(c: char) -> TaskState {
+        match c {
+            'R' => TaskState::Running,
+            'S' => TaskState::Sleeping,
+            'D' => TaskState::DiskSleep,
+            'T' => TaskState::Stopped,
+            't' => TaskState::TracingStop,
+            'Z' => TaskState::Zombie,
+            'X' | 'x' => TaskState::Dead,
+            'I' => TaskState::Idle,
+            c => TaskState::Other(c),
+        }
+    }
+}
+
+/// Scheduling information the kernel keeps about a thread.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TaskStat {
+    /// Kernel thread ID.
+    pub lwp: libc::pid_t,
+    /// Scheduling state.
+    pub state: TaskState,
+    /// Time spent in user mode.
+    pub utime: Duration,
+    /// Time spent in kernel mode.
+    pub stime: Duration,
+    /// CPU the thread last ran on.
+    pub last_cpu: i32,
+    /// Nice value, from 19 (low priority) to -20 (high priority).
+    pub nice: i32,
+    /// Number of context switches because the thread gave up the CPU.
+    pub voluntary_ctxt_switches: u64,
+    /// Number of context switches because the thread was preempted.
+    pub nonvoluntary_ctxt_switches: u64,
+}
+
+impl TaskStat {
+    /// Reads the information for thread `lwp` of process `pid`.
+    pub fn read(pid: libc::pid_t, lwp: libc::pid_t) -> io::Result<TaskStat> {
+        let dir = format!("/proc/{}/task/{}", pid, lwp);
+        let stat = std::fs::read_to_string(format!("{}/stat", dir))?;
+        let status = std::fs::read_to_string(format!("{}/status", dir))?;
+        TaskStat::parse(lwp, &stat, &status, clock_ticks())
+    }
+
+    fn parse(lwp: libc::pid_t, stat: &str, status: &str, ticks_per_sec: u64) -> io::Result<TaskStat> {
+        // The command name in parentheses may contain spaces and parentheses itself, so start
+        // after the last closing parenthesis.
+        let fields: Vec<&str> = stat.rfind(')')
+            .map(|end| stat[end + 1..].split_whitespace().collect())
+            .ok_or_else(|| invalid("missing command name in stat"))?;
+        // Field numbers as in proc(5); the first field after the command name is field 3.
+        let field = |n: usize| fields.get(n - 3).copied().ok_or_else(|| invalid(&format!("missing field {} in stat", n)));
+        let number = |n: usize| field(n)?.parse::<i64>().map_err(|e| invalid(&format!("field {} in stat: {}", n, e)));
+        let ticks = |n: usize| -> io::Result<Duration> {
+            let t = number(n)? as u64;
+            Ok(Duration::from_secs(t / ticks_per_sec) + Duration::from_nanos(t % ticks_per_sec * 1_000_000_000 / ticks_per_sec))
+        };
+
+        let status_value = |key: &str| -> io::Result<u64> {
+            status_field(status, key)
+                .ok_or_else(|| invalid(&format!("missing {} in status", key)))?
+                .parse()
+                .map_err(|e| invalid(&format!("{} in status: {}", key, e)))
+        };
+
+        Ok(TaskStat {
+            lwp,
+            state: field(3)?.chars().next().map(TaskState::from).ok_or_else(|| invalid("empty state in stat"))?,
+            utime: ticks(14)?,
+            stime: ticks(15)?,
+            nice: number(19)? as i32,
+            last_cpu: number(39)? as i32,
+            voluntary_ctxt_switches: status_value("voluntary_ctxt_switches")?,
+            nonvoluntary_ctxt_switches: status_value("nonvoluntary_ctxt_switches")?,
+        })
+    }
+}
+
+/// Returns the value of `key` in the contents of a `status` file.
+fn status_field<'a>(status: &'a str, key: &str) -> Option<&'a str> {
+    status.lines()
+        .find_map(|line| line.strip_prefix(key).and_then(|rest| rest.strip_prefix(':')))
+        .map(str::trim)
+}
+
+/// Returns the ID of the thread tracing the process or thread `pid`, or 0 if it isn't traced.
+pub fn tracer(pid: libc::pid_t) -> io::Result<libc::pid_t> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid))?;
+    status_field(&status, "TracerPid")
+        .and_then(|tracer| tracer.parse().ok())
+        .ok_or_else(|| invalid("no TracerPid in status"))
+}
+
+/// Finds out why attaching to the process or thread `pid` with ptrace failed with `err`.
+///
+/// Follows the checks of the kernel's `ptrace_may_access()` and the Yama LSM.
+pub fn diagnose_attach(pid: libc::pid_t, err: &io::Error) -> AttachCause {
+    let status = match std::fs::read_to_string(format!("/proc/{}/status", pid)) {
+        Ok(status) => status,
+        Err(_) => return AttachCause::Exited,
+    };
+    let zombie = status_field(&status, "State").is_some_and(|state| state.starts_with('Z'));
+    if err.raw_os_error() == Some(libc::ESRCH) || zombie {
+        return AttachCause::Exited;
+    }
+    if err.raw_os_error() != Some(libc::EPERM) {
+        return AttachCause::Unknown;
+    }
+    if let Some(tracer) = status_field(&status, "TracerPid").and_then(|pid| pid.parse().ok()).filter(|&pid| pid != 0) {
+        return AttachCause::AlreadyTraced(tracer);
+    }
+    let scope = std::fs::read_to_string("/proc/sys/kernel/yama/ptrace_scope").ok()
+        .and_then(|scope| scope.trim().parse().ok())
+        .unwrap_or(0);
+    if scope >= 3 {
+        return AttachCause::PtraceScope(scope);
+    }
+    if has_cap_sys_ptrace() {
+        return AttachCause::Unknown;
+    }
+    let uid = unsafe { libc::getuid() };
+    // The target's real, effective and saved user IDs must all match ours.
+    let target_uid = status_field(&status, "Uid")
+        .and_then(|uids| uids.split_whitespace().take(3).filter_map(|uid| uid.parse().ok()).find(|&target_uid| target_uid != uid));
+    if let Some(target_uid) = target_uid {
+        return AttachCause::DifferentUser { uid, target_uid };
+    }
+    // The kernel makes root the owner of /proc/<pid> for non-dumpable processes.
+    if uid != 0 && std::fs::metadata(format!("/proc/{}", pid)).is_ok_and(|meta| meta.uid() == 0) {
+        return AttachCause::NotDumpable;
+    }
+    if scope == 2 || (scope == 1 && !is_descendant(pid)) {
+        return AttachCause::PtraceScope(scope);
+    }
+    AttachCause::Unknown
+}
+
+/// Returns whether we have CAP_SYS_PTRACE in our effective capability set.
+fn has_cap_sys_ptrace() -> bool {
+    const CAP_SYS_PTRACE: u32 = 19;
+    std::fs::read_to_string("/proc/self/status").ok()
+        .and_then(|status| status_field(&status, "CapEff").and_then(|caps| u64::from_str_radix(caps, 16).ok()))
+        .is_some_and(|caps| caps & (1 << CAP_SYS_PTRACE) != 0)
+}
+
+/// Returns whether the process `pid` is a descendant of ours.
+fn is_descendant(pid: libc::pid_t) -> bool {
+    let own_pid = std::process::id() as libc::pid_t;
+    let mut pid = pid;
+    while pid > 1 {
+        let ppid = std::fs::read_to_string(format!("/proc/{}/status", pid)).ok()
+            .and_then(|status| status_field(&status, "PPid").and_then(|ppid| ppid.parse().ok()));
+        match ppid {
+            Some(ppid) if ppid == own_pid => return true,
+            Some(ppid) => pid = ppid,
+            None => return false,
+        }
+    }
+    false
+}
+
+/// Reads the name of thread `lwp` of process `pid`, as set with pthread_setname_np(3) or
+/// prctl(PR_SET_NAME).
+pub fn read_comm(pid: libc::pid_t, lwp: libc::pid_t) -> io::Result<String> {
+    let comm = std::fs::read(format!("/proc/{}/task/{}/comm", pid, lwp))?;
+    // The kernel doesn't require names to be UTF-8.
+    Ok(String::from_utf8_lossy(&comm).trim_end_matches('\n').to_string())
+}
+
+/// A named mapping of the process, as listed in `/proc/<pid>/maps` or the NT_FILE note of a core.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mapping {
+    pub start: usize,
+    pub end: usize,
+    /// Offset into the file in bytes.
+    pub offset: usize,
+    /// File name, or a pseudo-path like `[vdso]` for other mappings with a name.
+    pub name: String,
+}
+
+impl Mapping {
+    pub fn contains(&self, addr: usize) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+/// Returns the named mappings of process `pid` from `/proc/<pid>/maps`.
+pub fn read_maps(pid: libc::pid_t) -> io::Result<Vec<Mapping>> {
+    let maps = proc_maps::get_process_maps(pid)?;
+    Ok(maps.iter()
+        .filter_map(|map| map.filename().as_ref().map(|name| Mapping {
+            start: map.start(),
+            end: map.start() + map.size(),
+            offset: map.offset,
+            name: name.clone(),
+        }))
+        .collect())
+}
+
+/// Returns the number of clock ticks per second used for CPU times in /proc.
+fn clock_ticks() -> u64 {
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as u64,
+        _ => 100,
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_works() {
+        let stat = "4243 (my (worker) 1) S 4242 4242 4000 34817 4242 1077936192 120 0 0 0 250 75 0 0 20 5 3 0 123456 1000000 200 18446744073709551615 1 1 0 0 0 0 0 4096 0 0 0 0 -1 7 0 0 0 0 0 0 0 0 0 0 0 0 0\n";
+        let status = "Name:\tmy (worker) 1\nState:\tS (sleeping)\nvoluntary_ctxt_switches:\t17\nnonvoluntary_ctxt_switches:\t3\n";
+        let task = TaskStat::parse(4243, stat, status, 100).unwrap();
+        assert_eq!(task, TaskStat {
+            lwp: 4243,
+            state: TaskState::Sleeping,
+            utime: Duration::from_millis(2500),
+            stime: Duration::from_millis(750),
+            last_cpu: 7,
+            nice: 5,
+            voluntary_ctxt_switches: 17,
+            nonvoluntary_ctxt_switches: 3,
+        });
+    }
+
+    #[test]
+    fn read_works() {
+        let pid = std::process::id() as libc::pid_t;
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+        let task = TaskStat::read(pid, tid).unwrap();
+        assert_eq!(task.lwp, tid);
+        assert_eq!(task.state, TaskState::Running);
+    }
+
+    #[test]
+    fn read_comm_works() {
+        let thread = std::thread::Builder::new().name("comm-test".to_string()).spawn(|| {
+            let pid = std::process::id() as libc::pid_t;
+            let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+            read_comm(pid, tid).unwrap()
+        }).unwrap();
+        assert_eq!(thread.join().unwrap(), "comm-test");
+    }
+}
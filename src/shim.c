@@ -0,0 +1,21 @@
+/*
+ * Exports the proc_service callbacks for copies of libthread_db that bind their symbols
+ * immediately (linked with -z now, or with LD_BIND_NOW set) and thus can't be loaded before the
+ * callbacks are defined. Each callback forwards to the function in its `<name>_impl` pointer,
+ * which src/dl.rs sets to the Rust implementation after loading the shim.
+ */
+
+#include <proc_service.h>
+
+#define CALLBACK(ret, name, params, args) \
+    ret (*name##_impl) params; \
+    ret name params { return name##_impl args; }
+
+CALLBACK(pid_t, ps_getpid, (struct ps_prochandle *h), (h))
+CALLBACK(ps_err_e, ps_pdread, (struct ps_prochandle *h, psaddr_t addr, void *buf, size_t size), (h, addr, buf, size))
+CALLBACK(ps_err_e, ps_pdwrite, (struct ps_prochandle *h, psaddr_t addr, const void *buf, size_t size), (h, addr, buf, size))
+CALLBACK(ps_err_e, ps_lgetregs, (struct ps_prochandle *h, lwpid_t lwp, prgregset_t regs), (h, lwp, regs))
+CALLBACK(ps_err_e, ps_lsetregs, (struct ps_prochandle *h, lwpid_t lwp, const prgregset_t regs), (h, lwp, regs))
+CALLBACK(ps_err_e, ps_lgetfpregs, (struct ps_prochandle *h, lwpid_t lwp, prfpregset_t *regs), (h, lwp, regs))
+CALLBACK(ps_err_e, ps_lsetfpregs, (struct ps_prochandle *h, lwpid_t lwp, const prfpregset_t *regs), (h, lwp, regs))
+CALLBACK(ps_err_e, ps_pglobal_lookup, (struct ps_prochandle *h, const char *object, const char *name, psaddr_t *addr), (h, object, name, addr))
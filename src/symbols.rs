@@ -0,0 +1,93 @@
+//! Reverse lookup of addresses in the target process.
+
+use std::ops::Range;
+
+/// A function or object defined by a module.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    /// Address in the target process.
+    pub address: usize,
+    /// Size from the ELF symbol table, 0 if unknown.
+    pub size: usize,
+}
+
+/// Symbols of an executable or library mapped into the process.
+struct ModuleSymbols {
+    /// Name as in /proc/<pid>/maps.
+    name: String,
+    /// Addresses mapped from the module.
+    range: Range<usize>,
+    /// Sorted by address.
+    symbols: Vec<Symbol>,
+}
+
+/// Address-sorted symbols of all modules of a process, see `Process::symbolize()`.
+#[derive(Default)]
+pub struct SymbolIndex {
+    /// Sorted by address range.
+    modules: Vec<ModuleSymbols>,
+}
+
+impl SymbolIndex {
+    /// Adds a module mapped at `range`.
+    pub fn add_module(&mut self, name: String, range: Range<usize>, mut symbols: Vec<Symbol>) {
+        symbols.sort_by_key(|sym| sym.address);
+        // Aliases share an address, keep only the first.
+        symbols.dedup_by_key(|sym| sym.address);
+        let pos = self.modules.partition_point(|module| module.range.start < range.start);
+        self.modules.insert(pos, ModuleSymbols { name, range, symbols });
+    }
+
+    /// Returns the module name, symbol name and offset into the symbol for `addr`.
+    ///
+    /// Symbols with a size only match addresses they contain. Symbols without a size extend to
+    /// the next symbol.
+    pub fn lookup(&self, addr: usize) -> Option<(&str, &str, usize)> {
+        let module = match self.modules.partition_point(|module| module.range.start <= addr) {
+            0 => return None,
+            pos => &self.modules[pos - 1],
+        };
+        if !module.range.contains(&addr) {
+            return None;
+        }
+        let symbol = match module.symbols.partition_point(|sym| sym.address <= addr) {
+            0 => return None,
+            pos => &module.symbols[pos - 1],
+        };
+        let offset = addr - symbol.address;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((&module.name, &symbol.name, offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sym(name: &str, address: usize, size: usize) -> Symbol {
+        Symbol { name: name.to_string(), address, size }
+    }
+
+    #[test]
+    fn lookup_works() {
+        let mut index = SymbolIndex::default();
+        index.add_module("/lib/libb.so".to_string(), 0x2000..0x3000, vec![sym("b", 0x2100, 0)]);
+        index.add_module("/lib/liba.so".to_string(), 0x1000..0x2000, vec![
+            sym("second", 0x1200, 0x10),
+            sym("first", 0x1100, 0x20),
+            sym("first_alias", 0x1100, 0x20),
+        ]);
+
+        assert_eq!(index.lookup(0x1100), Some(("/lib/liba.so", "first", 0)));
+        assert_eq!(index.lookup(0x111f), Some(("/lib/liba.so", "first", 0x1f)));
+        assert_eq!(index.lookup(0x1120), None);
+        assert_eq!(index.lookup(0x1208), Some(("/lib/liba.so", "second", 8)));
+        assert_eq!(index.lookup(0x2fff), Some(("/lib/libb.so", "b", 0xeff)));
+        assert_eq!(index.lookup(0x1050), None);
+        assert_eq!(index.lookup(0x3000), None);
+        assert_eq!(index.lookup(0x500), None);
+    }
+}
@@ -1,13 +1,19 @@
-/// Interface to libthread_db.so
-///
-/// See /usr/include/thread_db.h
+//! Interface to libthread_db.so
+//!
+//! See /usr/include/thread_db.h
+
+// The methods generated for the wrapper API mirror the C signatures.
+#![allow(clippy::too_many_arguments)]
 
-use dlopen_derive::WrapperApi;
-use dlopen::wrapper::{Container, WrapperApi};
+use std::path::Path;
 
+use parking_lot::ReentrantMutex;
+
+use crate::Error;
+use crate::dl::{Handle, Namespace};
 use crate::proc_service::{ProcHandle, PsAddr};
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 #[repr(C)]
 pub enum TdErr {
     /// No error.
@@ -54,13 +60,18 @@ pub enum TdErr {
     NoXregs,
     /// Thread has not yet allocated TLS for given module.
     TLSDefer,
-    NoTalloc,
     /// Version if libpthread and libthread_db do not match.
     Version,
     /// There is no TLS segment in the given module.
     NoTLS,
 }
 
+impl TdErr {
+    /// Alias of `TLSDefer`, as in thread_db.h.
+    #[allow(non_upper_case_globals)]
+    pub const NoTalloc: TdErr = TdErr::TLSDefer;
+}
+
 /// Handle for a process. Opaque type.
 pub type TdThrAgent = libc::c_void;
 
@@ -74,7 +85,8 @@ pub struct TdThrHandle {
 /// Possible thread states.  AnyState is a pseudo-state used to
 /// select threads regardless of state in td_ta_thr_iter().
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(C)]
 pub enum TdThrState {
     AnyState,
@@ -90,7 +102,8 @@ pub enum TdThrState {
 /// Thread type: user or system.  TD_THR_ANY_TYPE is a pseudo-type used
 /// to select threads regardless of type in td_ta_thr_iter().
 #[allow(dead_code)]
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(C)]
 pub enum TdThrType {
   AnyType,
@@ -99,7 +112,8 @@ pub enum TdThrType {
 }
 
 ///Bitmask of enabled events.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(C)]
 pub struct TdThrEvents {
     event_bits: [u32; 2],
@@ -189,55 +203,92 @@ impl std::fmt::Debug for TdThrInfo {
     }
 }
 
-#[derive(WrapperApi)]
-pub struct ThreadDb {
+/// Serializes all calls into libthread_db.
+///
+/// libthread_db keeps global state and is not reentrant. The lock is shared by all loaded copies
+/// because libraries loaded from the same file into the base namespace share their state. It is
+/// reentrant so that callbacks of td_ta_thr_iter can call other functions.
+static TD_LOCK: ReentrantMutex<()> = parking_lot::const_reentrant_mutex(());
+
+/// Declares the `ThreadDb` struct holding the given libthread_db functions, with a method calling
+/// each of them while holding `TD_LOCK`.
+macro_rules! thread_db_api {
+    ($($(#[$attr:meta])* fn $name:ident($($arg:ident: $ty:ty),*) -> $ret:ty;)*) => {
+        pub struct ThreadDb {
+            // Keeps the library loaded as long as the function pointers are in use.
+            _lib: Handle,
+            $($name: unsafe extern "C" fn($($ty),*) -> $ret,)*
+        }
+
+        impl ThreadDb {
+            /// Looks up all functions in the given library.
+            fn load(lib: Handle) -> Result<ThreadDb, String> {
+                unsafe {
+                    Ok(ThreadDb {
+                        $($name: std::mem::transmute::<*mut libc::c_void, unsafe extern "C" fn($($ty),*) -> $ret>(lib.symbol(stringify!($name))?),)*
+                        _lib: lib,
+                    })
+                }
+            }
+
+            $(
+                $(#[$attr])*
+                pub unsafe fn $name(&self, $($arg: $ty),*) -> $ret {
+                    let _guard = TD_LOCK.lock();
+                    (self.$name)($($arg),*)
+                }
+            )*
+        }
+    }
+}
+
+thread_db_api! {
     /// Initialize the thread debug support library.
-    td_init: unsafe extern "C" fn() -> TdErr,
+    fn td_init() -> TdErr;
     /// Generate new thread debug library handle for process PS.
-    td_ta_new: unsafe extern "C" fn(ps: *mut ProcHandle, ta: *mut *mut TdThrAgent) -> TdErr,
+    fn td_ta_new(ps: *mut ProcHandle, ta: *mut *mut TdThrAgent) -> TdErr;
     /// Free resources allocated for TA.
-    td_ta_delete: unsafe extern "C" fn(ta: *mut TdThrAgent) -> TdErr,
+    fn td_ta_delete(ta: *mut TdThrAgent) -> TdErr;
 
     /// Get number of currently running threads in process associated with TA.
-    td_ta_get_nthreads: unsafe extern "C" fn(ta: *const TdThrAgent, np: *mut i32) -> TdErr,
+    fn td_ta_get_nthreads(ta: *const TdThrAgent, np: *mut i32) -> TdErr;
 
     /// Enable collecting statistics for process associated with TA.
-    td_ta_enable_stats: unsafe extern "C" fn(ta: *mut TdThrAgent, enable: i32) -> TdErr,
+    fn td_ta_enable_stats(ta: *mut TdThrAgent, enable: i32) -> TdErr;
     /// Reset statistics.
-    td_ta_reset_stats: unsafe extern "C" fn(ta: *mut TdThrAgent) -> TdErr,
+    fn td_ta_reset_stats(ta: *mut TdThrAgent) -> TdErr;
     /// Retrieve statistics from process associated with TA.
-    td_ta_get_stats: unsafe extern "C" fn(ta: *const TdThrAgent, stats: *mut TdTaStats) -> TdErr,
+    fn td_ta_get_stats(ta: *const TdThrAgent, stats: *mut TdTaStats) -> TdErr;
 
     /// Call for each thread in a process associated with TA the callback function CALLBACK.
     /// From looking at the glibc implementation:
-    ///  - Return value of `callback`: 0 => ok, _ => error
+    ///  - Return value of `callback`: 0 => ok, _ => stop iterating and return `TdErr::DbErr`
     ///  - `state`: must be `TdThrState::AnyState`
-    ///  - `ti_prio`: minimum priority (probably 0 for all)
+    ///  - `ti_prio`: minimum priority (0 for all threads with the default scheduling policy)
     ///  - `ti_sigmask` and `ti_user_flags` are unused
-    td_ta_thr_iter: unsafe extern "C" fn(ta: *mut TdThrAgent, callback: unsafe extern "C" fn(handle: *const TdThrHandle, cbdata: *mut libc::c_void) -> i32, cbdata: *mut libc::c_void, state: TdThrState, pri: i32, ti_sigmask: *mut libc::sigset_t, ti_user_flags: u32) -> TdErr,
+    fn td_ta_thr_iter(ta: *mut TdThrAgent, callback: unsafe extern "C" fn(handle: *const TdThrHandle, cbdata: *mut libc::c_void) -> i32, cbdata: *mut libc::c_void, state: TdThrState, pri: i32, ti_sigmask: *mut libc::sigset_t, ti_user_flags: u32) -> TdErr;
 
 
     /// Validate that TH is a thread handle.
-    td_thr_validate: unsafe extern "C" fn(handle: *const TdThrHandle) -> TdErr,
+    fn td_thr_validate(handle: *const TdThrHandle) -> TdErr;
 
     /// Return information about thread TH.
-    td_thr_get_info: unsafe extern "C" fn(handle: *const TdThrHandle, info: *mut TdThrInfo) -> TdErr,
-}
+    fn td_thr_get_info(handle: *const TdThrHandle, info: *mut TdThrInfo) -> TdErr;
 
-pub fn open_lib() -> Container<ThreadDb> {
-    dummy();
-    eprintln!("open_lib");
-    let container: Container<ThreadDb> = unsafe { Container::load("libthread_db.so") }.unwrap();
-    let res = unsafe { container.td_init() };
-    eprintln!("td_init -> {:?}", res);
-    container
+    /// Retrieve general register contents of thread TH. The thread must be stopped.
+    fn td_thr_getgregs(handle: *const TdThrHandle, gregs: *mut libc::user_regs_struct) -> TdErr;
 }
 
-/// Dummy function to fool dead code elimination.
-fn dummy() {
-    unsafe { 
-        use crate::proc_service::*;
-        let mut handle = ProcHandle { pid: 0, symbols: std::collections::HashMap::new() };
-        ps_getpid(&mut handle);
+/// File names tried by `Library::try_new()`, in order.
+pub const DEFAULT_LIB_NAMES: &[&str] = &["libthread_db.so.1", "libthread_db.so"];
+
+/// Loads the libthread_db at `path` into the given namespace and initializes it.
+pub fn open_lib(path: &Path, namespace: Namespace) -> Result<ThreadDb, Error> {
+    log::debug!(path:% = path.display(), namespace:? = namespace; "loading {}", path.display());
+    let load_err = |e| Error::Load(format!("{}: {}", path.display(), e));
+    let api = ThreadDb::load(Handle::open(path, namespace).map_err(load_err)?).map_err(load_err)?;
+    match unsafe { api.td_init() } {
+        TdErr::Ok => Ok(api),
+        err => Err(Error::Load(format!("{}: td_init failed: {:?}", path.display(), err))),
     }
 }
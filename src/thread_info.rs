@@ -0,0 +1,128 @@
+//! Owned version of the thread information returned by libthread_db.
+
+use std::ops::Range;
+
+use nix::sys::signal::{SigSet, Signal};
+
+use crate::procfs::TaskStat;
+use crate::thread_db::{TdThrEvents, TdThrInfo, TdThrState, TdThrType};
+
+/// Information about a thread, see `Thread::thread_info()`.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ThreadInfo {
+    /// Thread ID returned by pthread_create().
+    pub tid: libc::pthread_t,
+    /// Kernel thread ID.
+    pub lwp: libc::pid_t,
+    /// Thread name, see `Thread::name()`. `None` if it couldn't be read.
+    pub name: Option<String>,
+    /// Start function passed to pthread_create(), 0 for the main thread.
+    pub start_func: usize,
+    /// Stack of the thread, empty if not reported by libthread_db (glibc doesn't).
+    pub stack: Range<usize>,
+    /// Pointer to thread-local data.
+    pub tls: usize,
+    /// Thread state. glibc always reports `TdThrState::Active`, see `task` for the kernel's view.
+    pub state: TdThrState,
+    /// Type of the thread (system vs user thread).
+    pub thread_type: TdThrType,
+    /// Thread priority.
+    pub priority: i32,
+    /// Signal mask.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_sigset"))]
+    pub sigmask: SigSet,
+    /// Set of pending signals.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "serialize_sigset"))]
+    pub pending: SigSet,
+    /// Set of enabled events.
+    pub events: TdThrEvents,
+    /// Whether the thread is suspended by the debugger.
+    pub db_suspended: bool,
+    /// Whether event reporting is enabled for the thread.
+    pub traceme: bool,
+    /// Scheduling information from /proc, `None` if it couldn't be read (e.g. because the thread
+    /// exited in the meantime).
+    pub task: Option<TaskStat>,
+}
+
+impl From<&TdThrInfo> for ThreadInfo {
+    fn from(info: &TdThrInfo) -> ThreadInfo {
+        let stack_base = info.ti_stkbase as usize;
+        ThreadInfo {
+            tid: info.ti_tid,
+            lwp: info.ti_lid,
+            name: None,
+            start_func: info.ti_startfunc as usize,
+            stack: stack_base..stack_base + info.ti_stksize as usize,
+            tls: info.ti_tls as usize,
+            state: info.ti_state,
+            thread_type: info.ti_type,
+            priority: info.ti_pri,
+            sigmask: sigset_from_raw(&info.ti_sigmask),
+            pending: sigset_from_raw(&info.ti_pending),
+            events: info.ti_events.clone(),
+            db_suspended: info.ti_db_suspended != 0,
+            traceme: info.ti_traceme != 0,
+            task: None,
+        }
+    }
+}
+
+/// Converts a raw `sigset_t` to nix's SigSet.
+fn sigset_from_raw(raw: &libc::sigset_t) -> SigSet {
+    let mut set = SigSet::empty();
+    for signal in Signal::iterator() {
+        if unsafe { libc::sigismember(raw, signal as libc::c_int) } == 1 {
+            set.add(signal);
+        }
+    }
+    set
+}
+
+/// Returns the signals contained in the set.
+fn signals(set: &SigSet) -> Vec<Signal> {
+    Signal::iterator().filter(|&signal| set.contains(signal)).collect()
+}
+
+#[cfg(feature = "serde")]
+fn serialize_sigset<S: serde::Serializer>(set: &SigSet, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(signals(set).iter().map(|signal| signal.as_ref()))
+}
+
+// SigSet doesn't implement Debug.
+impl std::fmt::Debug for ThreadInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ThreadInfo")
+            .field("tid", &self.tid)
+            .field("lwp", &self.lwp)
+            .field("name", &self.name)
+            .field("start_func", &format_args!("{:#x}", self.start_func))
+            .field("stack", &format_args!("{:#x}..{:#x}", self.stack.start, self.stack.end))
+            .field("tls", &format_args!("{:#x}", self.tls))
+            .field("state", &self.state)
+            .field("thread_type", &self.thread_type)
+            .field("priority", &self.priority)
+            .field("sigmask", &signals(&self.sigmask))
+            .field("pending", &signals(&self.pending))
+            .field("events", &self.events)
+            .field("db_suspended", &self.db_suspended)
+            .field("traceme", &self.traceme)
+            .field("task", &self.task)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sigset_from_raw_works() {
+        let mut expected = SigSet::empty();
+        expected.add(Signal::SIGINT);
+        expected.add(Signal::SIGUSR2);
+        let set = sigset_from_raw(expected.as_ref());
+        assert_eq!(signals(&set), vec![Signal::SIGINT, Signal::SIGUSR2]);
+    }
+}
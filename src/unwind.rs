@@ -0,0 +1,204 @@
+//! Stack unwinding with DWARF call frame information.
+//!
+//! Each frame is unwound with the CFI from the `.eh_frame` or `.debug_frame` section of the module
+//! containing its program counter. Code without CFI (e.g. JIT code or the vDSO) is unwound by
+//! following the frame pointer chain instead. Only x86-64 is supported.
+
+// The DWARF register numbers and the `user_regs_struct` fields below are x86-64's.
+#[cfg(not(target_arch = "x86_64"))]
+compile_error!("stack unwinding is only implemented for x86-64");
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use gimli::{BaseAddresses, CfaRule, DebugFrame, EhFrame, LittleEndian, Register, RegisterRule, UnwindContext, UnwindSection, UnwindTableRow};
+use log::debug;
+
+use crate::elf::{ElfFile, ElfFiles};
+use crate::proc_service::ProcHandle;
+use crate::procfs::Mapping;
+
+/// Maximum number of frames returned, in case the stack is corrupted.
+const MAX_FRAMES: usize = 256;
+
+/// DWARF register numbers, see the x86-64 System V ABI.
+const RBP: usize = 6;
+const RSP: usize = 7;
+/// Return address column, i.e. the program counter of the caller.
+const RA: usize = 16;
+
+/// Registers of a frame, indexed by DWARF register number. `None` if unknown.
+type Registers = [Option<usize>; 17];
+
+/// A stack frame, see `Thread::backtrace()`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Frame {
+    /// Program counter. Except for the innermost frame, this is the return address of a call.
+    pub pc: usize,
+    /// Stack pointer.
+    pub sp: usize,
+    /// Canonical frame address, i.e. the value of the stack pointer before the call that created
+    /// this frame. `None` if the frame couldn't be unwound.
+    pub cfa: Option<usize>,
+    /// Name of the mapping containing `pc` as shown in /proc/<pid>/maps, e.g. the path of a
+    /// library. `None` for anonymous memory, and for cores also for mappings other than files
+    /// like the vDSO.
+    pub module: Option<String>,
+}
+
+/// An executable or library mapped into the process.
+struct Module {
+    /// Difference between the addresses in memory and in the ELF file.
+    bias: usize,
+    /// `None` if the file couldn't be read.
+    file: Option<Rc<ElfFile>>,
+}
+
+impl Module {
+    /// Looks up the file `name` of the process, mapped at `start` with offset 0.
+    fn load(files: &ElfFiles, name: &str, start: usize) -> Module {
+        match files.get(name) {
+            Ok(file) => Module { bias: start.wrapping_sub(file.base), file: Some(file) },
+            Err(e) => {
+                debug!(path = name; "couldn't parse {}: {}", name, e);
+                Module { bias: start, file: None }
+            }
+        }
+    }
+
+    /// Returns the CFI row for `pc`, an address in memory.
+    fn unwind_row(&self, ctx: &mut UnwindContext<usize>, pc: usize) -> Option<UnwindTableRow<usize>> {
+        let file = self.file.as_ref()?;
+        let address = pc.wrapping_sub(self.bias) as u64;
+        if let Some(section) = &file.eh_frame {
+            let bases = BaseAddresses::default().set_eh_frame(section.addr).set_text(file.text_addr);
+            let eh_frame = EhFrame::new(&section.data, LittleEndian);
+            if let Ok(row) = eh_frame.unwind_info_for_address(&bases, ctx, address, EhFrame::cie_from_offset) {
+                return Some(row.clone());
+            }
+        }
+        if let Some(section) = &file.debug_frame {
+            let mut debug_frame = DebugFrame::new(&section.data, LittleEndian);
+            debug_frame.set_address_size(8);
+            if let Ok(row) = debug_frame.unwind_info_for_address(&BaseAddresses::default(), ctx, address, DebugFrame::cie_from_offset) {
+                return Some(row.clone());
+            }
+        }
+        None
+    }
+}
+
+/// Unwinds the stack starting with the given registers.
+///
+/// The thread of a live process must be stopped with `ProcHandle::stop_lwp()`; the stack is read
+/// through it. The CFI is taken from `files`.
+pub fn backtrace(handle: &ProcHandle, files: &ElfFiles, regs: &libc::user_regs_struct) -> Result<Vec<Frame>, std::io::Error> {
+    let maps = handle.mappings()?;
+    let mut modules: HashMap<String, Module> = HashMap::new();
+    let mut ctx = UnwindContext::new();
+    let read_word = |addr: usize| {
+        let mut buf = [0u8; std::mem::size_of::<usize>()];
+        handle.read(addr, &mut buf).ok().map(|()| usize::from_ne_bytes(buf))
+    };
+
+    let mut registers: Registers = [
+        regs.rax, regs.rdx, regs.rcx, regs.rbx, regs.rsi, regs.rdi, regs.rbp, regs.rsp,
+        regs.r8, regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15, regs.rip,
+    ].map(|reg| Some(reg as usize));
+
+    let mut frames: Vec<Frame> = Vec::new();
+    while let (Some(pc), Some(sp)) = (registers[RA], registers[RSP]) {
+        if pc == 0 || frames.len() == MAX_FRAMES {
+            break;
+        }
+        let module = maps.iter().find(|map| map.contains(pc)).map(|map| map.name.clone());
+        // Return addresses point after the call, which may already be the next function.
+        let lookup_pc = if frames.is_empty() { pc } else { pc - 1 };
+        let row = module.as_ref().filter(|name| name.starts_with('/')).and_then(|name| {
+            modules.entry(name.clone())
+                .or_insert_with(|| Module::load(files, name, module_start(&maps, name)))
+                .unwind_row(&mut ctx, lookup_pc)
+        });
+        let next = match row {
+            Some(row) => step_cfi(&row, &registers, read_word),
+            None => step_frame_pointer(&registers, read_word),
+        };
+        frames.push(Frame { pc, sp, cfa: next.map(|(cfa, _)| cfa), module });
+        match next {
+            // The stack grows down, so the caller's frame is above.
+            Some((cfa, next)) if cfa > sp => registers = next,
+            _ => break,
+        }
+    }
+    Ok(frames)
+}
+
+/// Returns the address the file `name` is mapped at, i.e. the start of the mapping with offset 0.
+fn module_start(maps: &[Mapping], name: &str) -> usize {
+    maps.iter()
+        .find(|map| map.name == name)
+        .map(|map| map.start - map.offset)
+        .unwrap_or(0)
+}
+
+/// Computes the CFA and the caller's registers using the CFI row of the frame.
+fn step_cfi<F>(row: &UnwindTableRow<usize>, registers: &Registers, read_word: F) -> Option<(usize, Registers)>
+    where F: Fn(usize) -> Option<usize>
+{
+    let cfa = match row.cfa() {
+        CfaRule::RegisterAndOffset { register, offset } => {
+            registers.get(register.0 as usize).copied().flatten()?.wrapping_add(*offset as usize)
+        }
+        // DWARF expressions are only used in a few places like PLT entries.
+        CfaRule::Expression(_) => return None,
+    };
+    let mut next: Registers = [None; 17];
+    for (reg, value) in next.iter_mut().enumerate() {
+        *value = match row.register(Register(reg as u16)) {
+            // Registers without a rule are callee-saved registers that weren't modified. An
+            // undefined return address marks the outermost frame.
+            RegisterRule::Undefined if reg == RA => None,
+            RegisterRule::Undefined | RegisterRule::SameValue => registers[reg],
+            RegisterRule::Offset(offset) => read_word(cfa.wrapping_add(offset as usize)),
+            RegisterRule::ValOffset(offset) => Some(cfa.wrapping_add(offset as usize)),
+            RegisterRule::Register(other) => registers.get(other.0 as usize).copied().flatten(),
+            _ => None,
+        };
+    }
+    next[RSP] = Some(cfa);
+    Some((cfa, next))
+}
+
+/// Computes the CFA and the caller's registers assuming a standard frame pointer setup
+/// (`push rbp; mov rbp, rsp`).
+fn step_frame_pointer<F>(registers: &Registers, read_word: F) -> Option<(usize, Registers)>
+    where F: Fn(usize) -> Option<usize>
+{
+    let rbp = registers[RBP].filter(|&rbp| rbp != 0)?;
+    let cfa = rbp.wrapping_add(16);
+    let mut next: Registers = [None; 17];
+    next[RBP] = read_word(rbp);
+    next[RA] = read_word(rbp.wrapping_add(8));
+    next[RSP] = Some(cfa);
+    Some((cfa, next))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_frame_pointer_works() {
+        let memory: HashMap<usize, usize> = [(0x1000, 0x1040), (0x1008, 0x4242)].iter().copied().collect();
+        let mut registers: Registers = [None; 17];
+        registers[RBP] = Some(0x1000);
+        registers[RSP] = Some(0xff0);
+        let (cfa, next) = step_frame_pointer(&registers, |addr| memory.get(&addr).copied()).unwrap();
+        assert_eq!(cfa, 0x1010);
+        assert_eq!((next[RA], next[RBP], next[RSP]), (Some(0x4242), Some(0x1040), Some(0x1010)));
+
+        registers[RBP] = Some(0);
+        assert!(step_frame_pointer(&registers, |addr| memory.get(&addr).copied()).is_none());
+    }
+}
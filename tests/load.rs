@@ -1,4 +1,21 @@
-use libthread_db::{Library};
+use libthread_db::{AttachCause, AttachError, AttachOptions, Error, Library, Process, TaskState, TdErr, ThreadFilter};
+
+/// Forks a child process that runs `threads` threads besides its main thread for `duration` and
+/// exits afterwards.
+fn spawn_threaded_child(threads: usize, duration: std::time::Duration) -> nix::unistd::Pid {
+    use nix::unistd::{fork, ForkResult};
+
+    match fork().unwrap() {
+        ForkResult::Child => {
+            for _ in 0..threads {
+                std::thread::spawn(move || std::thread::sleep(duration));
+            }
+            std::thread::sleep(duration);
+            std::process::exit(0);
+        },
+        ForkResult::Parent { child, .. } => child,
+    }
+}
 
 /// Tries to load the libthread_db library. This is already fairly complex because it requires
 /// several functions to be defined.
@@ -7,34 +24,880 @@ fn open_lib_works() {
     let _lib = Library::new();
 }
 
+/// Loads libthread_db by its versioned file name.
+#[test]
+fn open_by_name_works() {
+    Library::try_new().expect("try_new failed");
+    Library::open("libthread_db.so.1").expect("opening libthread_db.so.1 failed");
+}
+
+/// Loading a library that doesn't exist returns an error instead of panicking.
+#[test]
+fn open_missing_lib_fails() {
+    match Library::open("/nonexistent/libthread_db.so.1") {
+        Err(Error::Load(_)) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("loading a nonexistent library succeeded"),
+    }
+}
+
 /// Attaches to itself (via a forked child).
 #[test]
 fn self_attach_works() {
-    use nix::unistd::{fork, ForkResult};
+    let lib = Library::new();
+
+    let child = spawn_threaded_child(1, std::time::Duration::from_millis(2000));
+    let mut process = lib.attach(child.as_raw()).unwrap();
+    assert_eq!(process.get_nthreads().unwrap(), 2);
+
+    // Note: These functions are not actually implemented in glibc.
+    process.enable_stats(true).expect("enable_stats failed");
+    let _stats = process.get_stats().expect("get_stats failed");
+    process.reset_stats().expect("reset_stats failed");
+
+    let threads = process.threads().expect("getting threads failed");
+    assert_eq!(threads.len(), 2);
+
+    threads.iter().for_each(|t|t.validate().expect("thread is valid"));
+    let info = threads[0].info().expect("getting thread info failed");
+    println!("thread 0 info: {:?}", info);
+
+    let thread_info = threads[0].thread_info().expect("getting thread info failed");
+    println!("thread 0 thread_info: {:?}", thread_info);
+    assert_eq!(thread_info.lwp, info.ti_lid);
+    assert_eq!(thread_info.tid, info.ti_tid);
+
+    let task = threads[0].task_stat().expect("getting task stat failed");
+    assert_eq!(task.lwp, info.ti_lid);
+    assert_eq!(task.state, TaskState::Sleeping);
+    assert_eq!(thread_info.task.map(|t| t.lwp), Some(info.ti_lid));
+}
+
+/// Selects the libthread_db for a child process through the search path.
+#[test]
+fn for_process_works() {
+    let child = spawn_threaded_child(1, std::time::Duration::from_millis(2000));
+    let lib = Library::for_process(child.as_raw()).expect("no matching libthread_db");
+    let process = lib.attach(child.as_raw()).unwrap();
+    assert_eq!(process.get_nthreads().unwrap(), 2);
+
+    match Library::for_process_with_search_path(child.as_raw(), &["/nonexistent"]) {
+        Err(Error::Load(_)) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("loaded a libthread_db from a nonexistent directory"),
+    }
+}
+
+/// Skips a libthread_db in the search path that belongs to another glibc version.
+#[test]
+fn for_process_skips_other_versions() {
+    // Make a copy of the system's libthread_db that expects another nptl_version.
+    let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+    let libc_path = maps.lines()
+        .filter_map(|line| line.split_whitespace().nth(5))
+        .find(|path| path.ends_with("/libc.so.6"))
+        .expect("no libc mapped");
+    let system_lib = std::path::Path::new(libc_path).with_file_name("libthread_db.so.1");
+    let version = unsafe { std::ffi::CStr::from_ptr(libc::gnu_get_libc_version()) }.to_bytes();
+    let mut lib = std::fs::read(system_lib).unwrap();
+    // The version may be compared as an immediate operand rather than a string. Symbol versions
+    // like GLIBC_2.34 have to stay intact.
+    let positions: Vec<usize> = (1..lib.len() - version.len())
+        .filter(|&pos| &lib[pos..pos + version.len()] == version && lib[pos - 1] != b'_')
+        .collect();
+    assert!(!positions.is_empty(), "version not found in libthread_db");
+    for pos in positions {
+        lib[pos..pos + version.len()].fill(b'0');
+    }
+    let dir = std::env::temp_dir().join(format!("libthread_db-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("libthread_db.so.1"), lib).unwrap();
+    let dir = dir.to_str().unwrap();
+
+    let child = spawn_threaded_child(1, std::time::Duration::from_millis(2000));
+    match Library::for_process_with_search_path(child.as_raw(), &[dir]) {
+        Err(Error::ThreadDb(TdErr::Version)) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("loaded a libthread_db for another version"),
+    }
+    let lib = Library::for_process_with_search_path(child.as_raw(), &[dir, "$sdir"]).expect("no matching libthread_db");
+    let process = lib.attach(child.as_raw()).unwrap();
+    assert_eq!(process.get_nthreads().unwrap(), 2);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Loads two copies of libthread_db into separate link namespaces and uses them side by side.
+#[test]
+fn open_isolated_works() {
+    let lib1 = Library::open_isolated("libthread_db.so.1").expect("loading first copy failed");
+    let lib2 = Library::open_isolated("libthread_db.so.1").expect("loading second copy failed");
+
+    let child = spawn_threaded_child(1, std::time::Duration::from_millis(2000));
+    for lib in &[lib1, lib2] {
+        let process = lib.attach(child.as_raw()).unwrap();
+        assert_eq!(process.get_nthreads().unwrap(), 2);
+        assert_eq!(process.threads().unwrap().len(), 2);
+    }
+}
+
+/// Loads libthread_db with immediate binding (as for copies linked with -z now), which requires
+/// the callbacks to be defined when loading it. The dynamic linker only reads `LD_BIND_NOW` at
+/// startup, so this runs itself again in a new process.
+#[test]
+fn immediate_binding_works() {
+    if std::env::var_os("LD_BIND_NOW").is_none() {
+        let status = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "immediate_binding_works", "--test-threads=1"])
+            .env("LD_BIND_NOW", "1")
+            .status()
+            .unwrap();
+        assert!(status.success());
+        return;
+    }
+
+    let libs = [
+        Library::open("libthread_db.so.1").expect("loading into the base namespace failed"),
+        Library::open_isolated("libthread_db.so.1").expect("loading into a new namespace failed"),
+        Library::open_isolated("libthread_db.so.1").expect("loading a second copy failed"),
+    ];
+    let child = spawn_threaded_child(1, std::time::Duration::from_millis(2000));
+    for lib in &libs {
+        let process = lib.attach(child.as_raw()).unwrap();
+        assert_eq!(process.get_nthreads().unwrap(), 2);
+        assert_eq!(process.threads().unwrap().len(), 2);
+    }
+}
+
+/// Attaches to a process before it loads libpthread with `dlopen()` and picks it up on refresh.
+/// Since glibc 2.34 the threading library is part of libc, so the process is threaded from the
+/// start and only the new module is picked up; with older versions it becomes threaded. This runs
+/// itself again in a new process, which loads libpthread when told to through stdin.
+#[test]
+fn late_libpthread_works() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::io::FromRawFd;
+
+    if std::env::var_os("LIBTHREAD_DB_TEST_CHILD").is_some() {
+        // Write to the file descriptor directly, the test harness captures stdout.
+        let mut stdout = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(1) });
+        stdout.write_all(b"ready\n").unwrap();
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line).unwrap();
+        let handle = unsafe { libc::dlopen(b"libpthread.so.0\0".as_ptr() as *const libc::c_char, libc::RTLD_NOW) };
+        assert!(!handle.is_null());
+        stdout.write_all(b"loaded\n").unwrap();
+        // Wait for the parent to close stdin.
+        std::io::stdin().read_line(&mut line).unwrap();
+        return;
+    }
+
+    let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "late_libpthread_works", "--test-threads=1"])
+        .env("LIBTHREAD_DB_TEST_CHILD", "1")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = child.id() as i32;
+    let mut stdin = child.stdin.take().unwrap();
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    // A clone stops the child until the next call on the Process, so attach only once the
+    // harness started the test's thread. The harness announces the test on the same line.
+    assert!(lines.any(|line| line.unwrap().ends_with("ready")));
+
+    let lib = Library::for_process(pid).expect("no matching libthread_db");
+    let mut process = lib.attach(pid).unwrap();
+    let threaded = process.is_threaded();
+    writeln!(stdin, "load").unwrap();
+    assert!(lines.any(|line| line.unwrap() == "loaded"));
+    assert!(process.refresh().unwrap());
+    let tasks = std::fs::read_dir(format!("/proc/{}/task", pid)).unwrap().count();
+    assert_eq!(process.threads().unwrap().len(), tasks);
+    eprintln!("threaded before loading libpthread: {}", threaded);
+
+    drop(process);
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+}
+
+/// Library can be shared between threads.
+#[test]
+fn library_is_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Library>();
+}
+
+/// Attaches to several children concurrently from different threads using the same Library.
+#[test]
+fn concurrent_attach_works() {
+    let lib = Library::new();
+    let children: Vec<i32> = (0..4)
+        .map(|_| spawn_threaded_child(1, std::time::Duration::from_millis(10000)).as_raw())
+        .collect();
+
+    std::thread::scope(|scope| {
+        for &pid in &children {
+            let lib = &lib;
+            scope.spawn(move || {
+                for _ in 0..3 {
+                    let process = lib.attach(pid).expect("attach failed");
+                    assert_eq!(process.get_nthreads().unwrap(), 2);
+                    let threads = process.threads().unwrap();
+                    assert_eq!(threads.len(), 2);
+                    threads.iter().for_each(|t| { t.info().expect("getting thread info failed"); });
+                }
+            });
+        }
+    });
+
+    for pid in children {
+        unsafe { libc::kill(pid, libc::SIGKILL); }
+    }
+}
+
+/// Owned processes can be stored without keeping the Library around.
+#[test]
+fn attach_owned_works() {
+    struct Inspector {
+        process: Process<'static>,
+    }
+
+    let child = spawn_threaded_child(1, std::time::Duration::from_millis(2000));
+    let inspector = {
+        let lib = Library::new();
+        Inspector { process: lib.attach_owned(child.as_raw()).unwrap() }
+    };
+    assert_eq!(inspector.process.get_nthreads().unwrap(), 2);
+    assert_eq!(inspector.process.threads().unwrap().len(), 2);
+}
+
+/// Iterates over threads with filters and stops early.
+#[test]
+fn for_each_thread_works() {
+    use std::ops::ControlFlow;
 
+    let child = spawn_threaded_child(2, std::time::Duration::from_millis(2000));
     let lib = Library::new();
+    let process = lib.attach(child.as_raw()).unwrap();
+    assert_eq!(process.threads().unwrap().len(), 3);
+
+    let mut visited = 0;
+    process.for_each_thread(&ThreadFilter::new(), |_| {
+        visited += 1;
+        ControlFlow::Break(())
+    }).expect("for_each_thread failed");
+    assert_eq!(visited, 1);
+
+    // Threads with the default scheduling policy have priority 0.
+    let mut visited = 0;
+    process.for_each_thread(&ThreadFilter::new().min_priority(1), |_| {
+        visited += 1;
+        ControlFlow::Continue(())
+    }).expect("for_each_thread failed");
+    assert_eq!(visited, 0);
+
+    let main_thread = process.find_thread(&ThreadFilter::new(), |t| t.info().unwrap().ti_lid == child.as_raw())
+        .expect("find_thread failed")
+        .expect("main thread not found");
+    assert_eq!(main_thread.info().unwrap().ti_lid, child.as_raw());
+    assert!(process.find_thread(&ThreadFilter::new(), |_| false).unwrap().is_none());
+}
+
+/// Calls from the `for_each_thread()` callback don't lose the error of a callback that fails later
+/// in the iteration.
+#[test]
+fn nested_calls_keep_iteration_errors() {
+    use std::ops::ControlFlow;
+
+    /// Reads or writes a pointer in the child's memory.
+    fn access(pid: nix::unistd::Pid, addr: usize, value: &mut usize, write: bool) {
+        let local = libc::iovec { iov_base: value as *mut usize as *mut libc::c_void, iov_len: std::mem::size_of::<usize>() };
+        let remote = libc::iovec { iov_base: addr as *mut libc::c_void, iov_len: std::mem::size_of::<usize>() };
+        let copied = unsafe {
+            if write {
+                libc::process_vm_writev(pid.as_raw(), &local, 1, &remote, 1, 0)
+            } else {
+                libc::process_vm_readv(pid.as_raw(), &local, 1, &remote, 1, 0)
+            }
+        };
+        assert_eq!(copied, std::mem::size_of::<usize>() as isize, "{}", std::io::Error::last_os_error());
+    }
+
+    let child = spawn_threaded_child(2, std::time::Duration::from_millis(2000));
+    let lib = Library::new();
+    let process = lib.attach(child.as_raw()).unwrap();
+    // The size, count and offset of the list entry in glibc's struct pthread, as published for
+    // libthread_db. The child runs the same libc.
+    let pthread_list = unsafe { libc::dlsym(libc::RTLD_DEFAULT, b"_thread_db_pthread_list\0".as_ptr() as *const libc::c_char) } as *const u32;
+    assert!(!pthread_list.is_null());
+    let offset = unsafe { *pthread_list.add(2) } as usize;
+    const BAD_ADDRESS: usize = 0x1000;
+
+    let mut visited = 0;
+    let result = process.for_each_thread(&ThreadFilter::new(), |thread| {
+        visited += 1;
+        // libthread_db has copied this thread's entry already, so point the next pointer (the
+        // first field) of the following entry at unmapped memory. That entry may be the head of
+        // a list, whose next pointer is only read when the iteration gets to the list.
+        let mut next = 0;
+        access(child, thread.info().unwrap().ti_tid as usize + offset, &mut next, false);
+        if next != BAD_ADDRESS {
+            access(child, next, &mut { BAD_ADDRESS }, true);
+        }
+        ControlFlow::Continue(())
+    });
+    match result {
+        Err(Error::Callback(_, cause)) => assert_eq!(cause.callback, "ps_pdread"),
+        other => panic!("unexpected result after {} threads: {:?}", visited, other),
+    }
+
+    drop(process);
+    nix::sys::signal::kill(child, nix::sys::signal::Signal::SIGKILL).unwrap();
+    nix::sys::wait::waitpid(child, None).unwrap();
+}
+
+#[test]
+fn thread_name_works() {
+    use nix::unistd::{fork, ForkResult};
+
+    match fork().unwrap() {
+        ForkResult::Child => {
+            let thread = std::thread::Builder::new()
+                .name("worker-1".to_string())
+                .spawn(|| std::thread::sleep(std::time::Duration::from_millis(2000)))
+                .unwrap();
+            thread.join().unwrap();
+            std::process::exit(0);
+        },
+        ForkResult::Parent { child, .. } => {
+            let lib = Library::new();
+            let process = lib.attach(child.as_raw()).unwrap();
+            let worker = process.find_thread(&ThreadFilter::new(), |t| t.name().unwrap() == "worker-1")
+                .expect("find_thread failed")
+                .expect("named thread not found");
+            assert_ne!(worker.info().unwrap().ti_lid, child.as_raw());
+            assert_eq!(worker.thread_info().unwrap().name.as_deref(), Some("worker-1"));
+        },
+    }
+}
+
+#[inline(never)]
+fn sleep_in_backtrace_test() {
+    std::thread::sleep(std::time::Duration::from_millis(2000));
+    std::hint::black_box(());
+}
+
+#[test]
+fn backtrace_works() {
+    use nix::unistd::{fork, ForkResult};
 
     match fork().unwrap() {
         ForkResult::Child => {
-            let thread = std::thread::spawn(|| std::thread::sleep(std::time::Duration::from_millis(2000)));
+            let thread = std::thread::Builder::new()
+                .name("sleeper".to_string())
+                .spawn(sleep_in_backtrace_test)
+                .unwrap();
             thread.join().unwrap();
             std::process::exit(0);
         },
         ForkResult::Parent { child, .. } => {
-            let mut process = lib.attach(child.as_raw()).unwrap();
+            // Give the thread time to start sleeping.
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            let lib = Library::new();
+            let process = lib.attach(child.as_raw()).unwrap();
+            let sleeper = process.find_thread(&ThreadFilter::new(), |t| t.name().unwrap() == "sleeper")
+                .expect("find_thread failed")
+                .expect("sleeping thread not found");
+            let frames = sleeper.backtrace().expect("backtrace failed");
+            for frame in &frames {
+                println!("{:#x} sp={:#x} cfa={:?} {:?}", frame.pc, frame.sp, frame.cfa, frame.module);
+            }
+            // The child is a fork of this process, so the function is at the same address.
+            let function = sleep_in_backtrace_test as *const () as usize;
+            assert!(frames.iter().any(|f| f.pc > function && f.pc < function + 0x100), "caller not found in backtrace");
+            assert!(frames.windows(2).all(|w| w[0].sp < w[1].sp), "stack pointers not increasing");
+            let exe = std::env::current_exe().unwrap();
+            assert!(frames.iter().any(|f| f.module.as_deref() == exe.to_str()));
+
+            let (module, symbol, offset) = process.symbolize(function + 1).expect("function not found");
+            assert_eq!(module, exe.to_str().unwrap());
+            assert!(symbol.contains("sleep_in_backtrace_test"), "unexpected symbol {}", symbol);
+            assert_eq!(offset, 1);
+            let (_, symbol, _) = process.symbolize(frames[0].pc).expect("innermost frame not found");
+            assert!(symbol.contains("sleep"), "unexpected symbol {}", symbol);
+
+            // The thread keeps running afterwards.
+            assert!(sleeper.backtrace().is_ok());
+        },
+    }
+}
+
+#[test]
+fn attach_without_ptrace_works() {
+    let child = spawn_threaded_child(1, std::time::Duration::from_millis(2000));
+    let lib = Library::new();
+    // Another tracer prevents attaching with ptrace.
+    let traced = lib.attach(child.as_raw()).unwrap();
+    match lib.attach(child.as_raw()) {
+        Err(Error::Attach(AttachError { cause, error })) => {
+            // The tracer is this thread.
+            let tid = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+            assert_eq!(cause, AttachCause::AlreadyTraced(tid), "{}", error);
+        },
+        _ => panic!("attaching twice succeeded"),
+    }
+
+    let process = lib.attach_with(child.as_raw(), &AttachOptions::new().ptrace(false))
+        .expect("attaching without ptrace failed");
+    assert_eq!(process.get_nthreads().unwrap(), 2);
+    let threads = process.threads().unwrap();
+    assert_eq!(threads.len(), 2);
+    let info = threads[0].thread_info().unwrap();
+    assert_eq!(info.task.map(|t| t.state), Some(TaskState::Sleeping));
+    assert!(matches!(threads[0].backtrace(), Err(Error::Unsupported(_))));
+    drop(threads);
+    drop(process);
+    drop(traced);
+}
+
+#[test]
+fn read_only_attach_works() {
+    let child = spawn_threaded_child(1, std::time::Duration::from_millis(2000));
+    let lib = Library::new();
+    let mut process = lib.attach_with(child.as_raw(), &AttachOptions::new().read_only(true)).unwrap();
+    assert!(process.is_read_only());
+    assert!(matches!(process.enable_stats(true), Err(Error::ReadOnly)));
+    assert!(matches!(process.reset_stats(), Err(Error::ReadOnly)));
+    // Reading still works, including registers.
+    let threads = process.threads().unwrap();
+    assert_eq!(threads.len(), 2);
+    assert!(!threads[0].backtrace().unwrap().is_empty());
+    drop(threads);
+    drop(process);
+
+    let process = lib.attach(child.as_raw());
+    assert!(!process.unwrap().is_read_only());
+}
+
+/// Reports why attaching failed. The check for other users drops root privileges, so it only runs
+/// as root.
+#[test]
+fn attach_diagnostics_work() {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, setuid, ForkResult, Uid};
+
+    let target = spawn_threaded_child(0, std::time::Duration::from_millis(2000));
+
+    if Uid::effective().is_root() {
+        match fork().unwrap() {
+            ForkResult::Child => {
+                // Dropping root privileges also drops CAP_SYS_PTRACE.
+                setuid(Uid::from_raw(65534)).unwrap();
+                let result = Library::new().attach(target.as_raw()).map(|_| ());
+                match result {
+                    Err(Error::Attach(AttachError { cause: AttachCause::DifferentUser { uid: 65534, target_uid: 0 }, .. })) => std::process::exit(0),
+                    other => {
+                        eprintln!("unexpected result: {:?}", other);
+                        std::process::exit(1);
+                    }
+                }
+            },
+            ForkResult::Parent { child } => {
+                assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+            },
+        }
+    } else {
+        eprintln!("not running as root, skipping the check for other users");
+    }
+
+    nix::sys::signal::kill(target, nix::sys::signal::Signal::SIGKILL).unwrap();
+    waitpid(target, None).unwrap();
+    match Library::new().attach(target.as_raw()) {
+        Err(Error::Attach(err)) => {
+            assert_eq!(err.cause, AttachCause::Exited);
+            assert!(err.to_string().contains("has exited"), "unexpected message: {}", err);
+        },
+        _ => panic!("attaching to an exited process succeeded"),
+    }
+}
+
+/// Attaches while the process creates threads back to back. The kernel attaches threads created by
+/// attached threads on its own, so some threads are attached before we get to them.
+#[test]
+fn attach_during_thread_creation_works() {
+    use nix::sys::signal::{kill, Signal};
+    use nix::sys::wait::waitpid;
+    use nix::unistd::{fork, ForkResult};
+
+    let child = match fork().unwrap() {
+        ForkResult::Child => loop {
+            let threads: Vec<_> = (0..8).map(|_| std::thread::spawn(|| std::thread::sleep(std::time::Duration::from_millis(1)))).collect();
+            threads.into_iter().for_each(|t| t.join().unwrap());
+        },
+        ForkResult::Parent { child, .. } => child,
+    };
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    let lib = Library::new();
+    for _ in 0..100 {
+        let process = lib.attach(child.as_raw()).expect("attach failed");
+        process.detach().expect("detach failed");
+    }
+    // Threads attached by the kernel were detached as well.
+    for entry in std::fs::read_dir(format!("/proc/{}/task", child)).unwrap() {
+        // The thread may have exited in the meantime.
+        if let Ok(status) = std::fs::read_to_string(entry.unwrap().path().join("status")) {
+            assert!(status.lines().any(|line| line == "TracerPid:\t0"), "thread still traced: {}", status);
+        }
+    }
+    kill(child, Signal::SIGKILL).unwrap();
+    waitpid(child, None).unwrap();
+}
+
+#[test]
+fn new_threads_are_attached() {
+    use nix::unistd::{fork, ForkResult};
+
+    match fork().unwrap() {
+        ForkResult::Child => {
+            let first = std::thread::spawn(|| std::thread::sleep(std::time::Duration::from_millis(3000)));
+            std::thread::sleep(std::time::Duration::from_millis(500));
+            let second = std::thread::Builder::new()
+                .name("late".to_string())
+                .spawn(|| std::thread::sleep(std::time::Duration::from_millis(2000)))
+                .unwrap();
+            first.join().unwrap();
+            second.join().unwrap();
+            std::process::exit(0);
+        },
+        ForkResult::Parent { child, .. } => {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            let lib = Library::new();
+            let process = lib.attach(child.as_raw()).unwrap();
+            let lwps = process.lwps();
+            assert_eq!(lwps.len(), 2);
+            assert!(lwps.contains(&child.as_raw()));
+
+            // Registers can be read from threads other than the leader.
+            for thread in process.threads().unwrap() {
+                assert!(!thread.backtrace().unwrap().is_empty());
+            }
+
+            let mut lwps = process.lwps();
+            for _ in 0..50 {
+                if lwps.len() == 3 {
+                    break;
+                }
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                lwps = process.lwps();
+            }
+            assert_eq!(lwps.len(), 3, "new thread wasn't attached");
+            let late = process.find_thread(&ThreadFilter::new(), |t| t.name().unwrap() == "late")
+                .expect("find_thread failed")
+                .expect("new thread not found");
+            assert!(lwps.contains(&late.info().unwrap().ti_lid));
+            assert!(!late.backtrace().unwrap().is_empty());
+            drop(process);
+
+            // All threads were detached.
+            for lwp in lwps {
+                let status = std::fs::read_to_string(format!("/proc/{}/task/{}/status", child, lwp)).unwrap();
+                assert!(status.contains("TracerPid:\t0\n"), "thread {} is still traced", lwp);
+            }
+        },
+    }
+}
+
+#[test]
+fn stopped_process_stays_stopped() {
+    use nix::sys::signal::{kill, Signal};
+    use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+
+    let child = spawn_threaded_child(1, std::time::Duration::from_millis(2000));
+    // Let the child create its thread first.
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    kill(child, Signal::SIGSTOP).unwrap();
+    assert_eq!(waitpid(child, Some(WaitPidFlag::WUNTRACED)).unwrap(), WaitStatus::Stopped(child, Signal::SIGSTOP));
+
+    let lib = Library::new();
+    let process = lib.attach(child.as_raw()).unwrap();
+    for thread in process.threads().unwrap() {
+        assert!(!thread.backtrace().unwrap().is_empty());
+        assert_eq!(thread.thread_info().unwrap().task.map(|t| t.state), Some(TaskState::TracingStop));
+    }
+    drop(process);
+
+    let state = |lwp: i32| std::fs::read_to_string(format!("/proc/{}/task/{}/stat", child, lwp)).unwrap()
+        .rsplit(") ").next().unwrap().chars().next().unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    for entry in std::fs::read_dir(format!("/proc/{}/task", child)).unwrap() {
+        let lwp: i32 = entry.unwrap().file_name().to_str().unwrap().parse().unwrap();
+        assert_eq!(state(lwp), 'T', "thread {} was resumed", lwp);
+    }
+    kill(child, Signal::SIGCONT).unwrap();
+    assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0));
+}
+
+#[test]
+fn signals_are_delivered_while_attached() {
+    use nix::sys::signal::{kill, signal, SigHandler, Signal};
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    static RECEIVED: AtomicBool = AtomicBool::new(false);
+    extern "C" fn handler(_: libc::c_int) {
+        RECEIVED.store(true, Ordering::SeqCst);
+    }
+
+    match fork().unwrap() {
+        ForkResult::Child => {
+            unsafe { signal(Signal::SIGUSR1, SigHandler::Handler(handler)).unwrap() };
+            let thread = std::thread::spawn(|| std::thread::sleep(std::time::Duration::from_millis(1000)));
+            thread.join().unwrap();
+            std::process::exit(if RECEIVED.load(Ordering::SeqCst) { 0 } else { 1 });
+        },
+        ForkResult::Parent { child, .. } => {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            let lib = Library::new();
+            let process = lib.attach(child.as_raw()).unwrap();
+            kill(child, Signal::SIGUSR1).unwrap();
+            // The signal stops the receiving thread until it's processed here.
+            for thread in process.threads().unwrap() {
+                assert!(!thread.backtrace().unwrap().is_empty());
+            }
+            drop(process);
+            assert_eq!(waitpid(child, None).unwrap(), WaitStatus::Exited(child, 0), "signal was lost");
+        },
+    }
+}
+
+#[test]
+fn detach_works() {
+    use nix::sys::signal::{kill, Signal};
+    use nix::sys::wait::waitpid;
+
+    let child = spawn_threaded_child(1, std::time::Duration::from_millis(2000));
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    let lib = Library::new();
+    let process = lib.attach(child.as_raw()).unwrap();
+    let lwps = process.lwps();
+    assert_eq!(lwps.len(), 2);
+    process.detach().expect("detaching failed");
+    for lwp in lwps {
+        let status = std::fs::read_to_string(format!("/proc/{}/task/{}/status", child, lwp)).unwrap();
+        assert!(status.contains("TracerPid:\t0\n"), "thread {} is still traced", lwp);
+    }
+
+    // Detaching from a process that died in the meantime isn't an error.
+    let process = lib.attach(child.as_raw()).unwrap();
+    kill(child, Signal::SIGKILL).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    process.detach().expect("detaching from a dead process failed");
+    // We're the parent as well as the tracer, so detaching may have reaped the child.
+    let _ = waitpid(child, None);
+}
+
+#[test]
+fn exec_is_detected() {
+    use nix::unistd::{execv, fork, ForkResult};
+    use std::ffi::CString;
+
+    match fork().unwrap() {
+        ForkResult::Child => {
+            let _thread = std::thread::spawn(|| std::thread::sleep(std::time::Duration::from_millis(3000)));
+            std::thread::sleep(std::time::Duration::from_millis(500));
+            let sleep = CString::new("/bin/sleep").unwrap();
+            let _ = execv(&sleep, &[sleep.clone(), CString::new("2").unwrap()]);
+            std::process::exit(1);
+        },
+        ForkResult::Parent { child, .. } => {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            let lib = Library::new();
+            let process = lib.attach(child.as_raw()).unwrap();
             assert_eq!(process.get_nthreads().unwrap(), 2);
+            std::thread::sleep(std::time::Duration::from_millis(1000));
+            assert!(matches!(process.get_nthreads(), Err(Error::Exec)));
+            assert!(matches!(process.threads(), Err(Error::Exec)));
+            assert!(matches!(process.check_state(), Err(Error::Exec)));
+            process.detach().unwrap();
+        },
+    }
+}
+
+/// Detects an exec while iterating over the threads, even if the iteration itself succeeds. The
+/// thread handles are stale afterwards.
+#[test]
+fn exec_during_iteration_is_detected() {
+    use nix::unistd::{execv, fork, pipe, read, write, ForkResult};
+    use std::ffi::CString;
+    use std::ops::ControlFlow;
+
+    let (exec_read, exec_write) = pipe().unwrap();
+    match fork().unwrap() {
+        ForkResult::Child => {
+            let _threads: Vec<_> = (0..2).map(|_| std::thread::spawn(|| std::thread::sleep(std::time::Duration::from_millis(3000)))).collect();
+            // Exec once the parent is in the middle of the iteration.
+            read(exec_read, &mut [0]).unwrap();
+            let sleep = CString::new("/bin/sleep").unwrap();
+            let _ = execv(&sleep, &[sleep.clone(), CString::new("2").unwrap()]);
+            std::process::exit(1);
+        },
+        ForkResult::Parent { child, .. } => {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            let lib = Library::new();
+            let process = lib.attach(child.as_raw()).unwrap();
+            // Stop right after the exec, so that libthread_db doesn't notice it by failing to read
+            // the next thread.
+            let result = process.for_each_thread(&ThreadFilter::new(), |_| {
+                write(exec_write, &[0]).unwrap();
+                // The exec waits for the other threads to be reaped and is then reported as a
+                // ptrace event. lwps() processes both.
+                while process.check_state().is_ok() {
+                    process.lwps();
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                ControlFlow::Break(())
+            });
+            assert!(matches!(result, Err(Error::Exec)), "unexpected result: {:?}", result);
+            process.detach().unwrap();
+        },
+    }
+}
+
+#[test]
+fn exit_is_detected() {
+    use nix::sys::signal::{kill, Signal};
+    use nix::sys::wait::waitpid;
 
-            // Note: These functions are not actually implemented in glibc.
-            process.enable_stats(true).expect("enable_stats failed");
-            let stats = process.get_stats().expect("get_stats failed");
-            process.reset_stats().expect("reset_stats failed");
+    for &ptrace in &[true, false] {
+        let child = spawn_threaded_child(1, std::time::Duration::from_millis(3000));
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        let lib = Library::new();
+        let process = lib.attach_with(child.as_raw(), &AttachOptions::new().ptrace(ptrace)).unwrap();
+        let threads = process.threads().unwrap();
+        assert!(process.check_state().is_ok());
+        kill(child, Signal::SIGKILL).unwrap();
+        // Reap the child so that its pid could be reused. With ptrace the threads are reaped
+        // by the tracer, which is this process as well.
+        if !ptrace {
+            waitpid(child, None).unwrap();
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert!(matches!(process.get_nthreads(), Err(Error::Exited)), "ptrace: {}", ptrace);
+        assert!(matches!(threads[0].name(), Err(Error::Exited)));
+        assert!(matches!(threads[0].thread_info(), Err(Error::Exited)));
+        drop(threads);
+        process.detach().unwrap();
+    }
+}
 
-            let threads = process.threads().expect("getting threads failed");
-            assert_eq!(threads.len(), 2);
+/// Forks a child with `threads` threads besides its main thread, which run
+/// `sleep_in_backtrace_test()`, and makes it dump core. Returns
+/// its pid and the path of the core, or `None` if the system doesn't write cores to the working
+/// directory (e.g. with systemd-coredump).
+fn dump_core(threads: usize) -> Option<(nix::unistd::Pid, std::path::PathBuf)> {
+    use nix::sys::signal::{kill, Signal};
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::{fork, ForkResult};
 
-            threads.iter().for_each(|t|t.validate().expect("thread is valid"));
-            let info = threads[0].info().expect("getting thread info failed");
-            println!("thread 0 info: {:?}", info);
+    let dir = std::env::temp_dir().join(format!("libthread_db-core-{}-{}", std::process::id(), threads));
+    std::fs::create_dir_all(&dir).unwrap();
+    match fork().unwrap() {
+        ForkResult::Child => {
+            std::env::set_current_dir(&dir).unwrap();
+            unsafe {
+                let mut limit: libc::rlimit = std::mem::zeroed();
+                libc::getrlimit(libc::RLIMIT_CORE, &mut limit);
+                limit.rlim_cur = limit.rlim_max;
+                libc::setrlimit(libc::RLIMIT_CORE, &limit);
+            }
+            for _ in 0..threads {
+                std::thread::spawn(sleep_in_backtrace_test);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(2000));
+            std::process::exit(0);
+        },
+        ForkResult::Parent { child, .. } => {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            kill(child, Signal::SIGABRT).unwrap();
+            assert!(matches!(waitpid(child, None).unwrap(), WaitStatus::Signaled(_, Signal::SIGABRT, _)));
+            let core = ["core".to_string(), format!("core.{}", child)].iter()
+                .map(|name| dir.join(name))
+                .find(|path| path.exists());
+            if core.is_none() {
+                let pattern = std::fs::read_to_string("/proc/sys/kernel/core_pattern").unwrap_or_default();
+                println!("no core dumped, core_pattern is {}", pattern.trim());
+            }
+            core.map(|core| (child, core))
         },
     }
 }
+
+#[test]
+fn core_works() {
+    let comm = std::fs::read_to_string("/proc/thread-self/comm").unwrap().trim_end().to_string();
+    let (pid, core) = match dump_core(2) {
+        Some(dumped) => dumped,
+        None => return,
+    };
+    let lib = Library::new();
+    let mut process = lib.attach_core(&core).expect("opening core failed");
+    assert!(process.is_core());
+    assert!(process.is_read_only());
+    assert_eq!(process.pid(), pid.as_raw());
+    assert!(process.check_state().is_ok());
+    assert_eq!(process.get_nthreads().unwrap(), 3);
+
+    let lwps = process.lwps();
+    assert_eq!(lwps.len(), 3);
+    assert!(lwps.contains(&pid.as_raw()));
+    let threads = process.threads().unwrap();
+    let mut thread_lwps: Vec<_> = threads.iter().map(|thread| thread.info().unwrap().ti_lid).collect();
+    thread_lwps.sort();
+    assert_eq!(thread_lwps, lwps);
+    for thread in &threads {
+        thread.validate().unwrap();
+        let info = thread.thread_info().unwrap();
+        assert!(info.task.is_none());
+        if info.lwp != pid.as_raw() {
+            let (_, symbol, _) = process.symbolize(info.start_func).expect("start function not found");
+            assert!(!symbol.is_empty());
+        }
+        assert!(matches!(thread.task_stat(), Err(Error::Unsupported(_))));
+        if info.lwp == pid.as_raw() {
+            let name = thread.name().unwrap();
+            assert_eq!(info.name, Some(name.clone()));
+            // The child is a fork of this thread.
+            assert_eq!(name, comm);
+        } else {
+            assert!(matches!(thread.name(), Err(Error::Unsupported(_))));
+            assert!(info.name.is_none());
+        }
+
+        let frames = thread.backtrace().expect("backtrace failed");
+        assert!(frames.windows(2).all(|w| w[0].sp < w[1].sp), "stack pointers not increasing");
+        if info.lwp != pid.as_raw() {
+            // The child is a fork of this process, so the function is at the same address.
+            let function = sleep_in_backtrace_test as *const () as usize;
+            assert!(frames.iter().any(|f| f.pc > function && f.pc < function + 0x100), "caller not found in backtrace");
+        }
+    }
+    drop(threads);
+    assert!(matches!(process.enable_stats(true), Err(Error::ReadOnly)));
+    // Nothing changes in a core.
+    assert!(process.refresh().unwrap());
+    drop(process);
+
+    std::fs::remove_dir_all(core.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn bad_cores_fail() {
+    let lib = Library::new();
+    assert!(matches!(lib.attach_core("/nonexistent/core"), Err(Error::Core(_))));
+    // Not a core but an executable.
+    match lib.attach_core(std::env::current_exe().unwrap()) {
+        Err(Error::Core(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
+        _ => panic!("opened an executable as core"),
+    }
+}
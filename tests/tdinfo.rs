@@ -0,0 +1,123 @@
+use std::process::Command;
+
+/// Runs tdinfo on a forked child with a named thread.
+fn run_tdinfo(args: &[&str]) -> String {
+    use nix::unistd::{fork, ForkResult};
+
+    match fork().unwrap() {
+        ForkResult::Child => {
+            let thread = std::thread::Builder::new()
+                .name("tdinfo-worker".to_string())
+                .spawn(|| std::thread::sleep(std::time::Duration::from_millis(2000)))
+                .unwrap();
+            thread.join().unwrap();
+            std::process::exit(0);
+        },
+        ForkResult::Parent { child, .. } => {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            let output = Command::new(env!("CARGO_BIN_EXE_tdinfo"))
+                .args(args)
+                .arg(child.to_string())
+                .output()
+                .expect("running tdinfo failed");
+            nix::sys::signal::kill(child, nix::sys::signal::Signal::SIGKILL).unwrap();
+            nix::sys::wait::waitpid(child, None).unwrap();
+            assert!(output.status.success(), "tdinfo failed: {}", String::from_utf8_lossy(&output.stderr));
+            String::from_utf8(output.stdout).unwrap()
+        },
+    }
+}
+
+#[test]
+fn table_works() {
+    let output = run_tdinfo(&["--backtrace"]);
+    println!("{}", output);
+    assert!(output.starts_with("     LWP"));
+    assert!(output.contains("tdinfo-worker"));
+    assert!(output.contains("    #0 "));
+}
+
+#[test]
+fn json_works() {
+    let output = run_tdinfo(&["--json"]);
+    println!("{}", output);
+    let report: serde_json::Value = serde_json::from_str(&output).expect("invalid JSON");
+    assert!(report["pid"].is_i64());
+    let threads = report["threads"].as_array().unwrap();
+    let worker = threads.iter().find(|thread| thread["name"] == "tdinfo-worker").expect("worker thread missing");
+    assert_eq!(worker["task"]["state"], "Sleeping");
+    assert!(worker["start_symbol"]["name"].is_string());
+    assert!(worker.get("backtrace").is_none());
+}
+
+#[test]
+fn bad_arguments_fail() {
+    let output = Command::new(env!("CARGO_BIN_EXE_tdinfo")).args(["1234", "core.1234"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("only one pid or core"));
+
+    // Anything that isn't a pid is a core.
+    let output = Command::new(env!("CARGO_BIN_EXE_tdinfo")).arg("/nonexistent/core.1234").output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("could not read core file"));
+}
+
+/// Runs tdinfo on the core of a forked child with a named thread, unless the system doesn't
+/// write cores to the working directory.
+#[test]
+fn core_works() {
+    use nix::sys::signal::{kill, Signal};
+    use nix::sys::wait::waitpid;
+    use nix::unistd::{fork, ForkResult};
+
+    let dir = std::env::temp_dir().join(format!("tdinfo-core-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    match fork().unwrap() {
+        ForkResult::Child => {
+            std::env::set_current_dir(&dir).unwrap();
+            unsafe {
+                let mut limit: libc::rlimit = std::mem::zeroed();
+                libc::getrlimit(libc::RLIMIT_CORE, &mut limit);
+                limit.rlim_cur = limit.rlim_max;
+                libc::setrlimit(libc::RLIMIT_CORE, &limit);
+            }
+            std::thread::Builder::new()
+                .name("tdinfo-worker".to_string())
+                .spawn(|| std::thread::sleep(std::time::Duration::from_millis(2000)))
+                .unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(2000));
+            std::process::exit(0);
+        },
+        ForkResult::Parent { child, .. } => {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            kill(child, Signal::SIGABRT).unwrap();
+            waitpid(child, None).unwrap();
+            let core = match ["core".to_string(), format!("core.{}", child)].iter().map(|name| dir.join(name)).find(|path| path.exists()) {
+                Some(core) => core,
+                None => {
+                    println!("no core dumped");
+                    return;
+                }
+            };
+            let output = Command::new(env!("CARGO_BIN_EXE_tdinfo"))
+                .args(["--json", "--backtrace"])
+                .arg(&core)
+                .output()
+                .expect("running tdinfo failed");
+            assert!(output.status.success(), "tdinfo failed: {}", String::from_utf8_lossy(&output.stderr));
+            let output = String::from_utf8(output.stdout).unwrap();
+            println!("{}", output);
+            let report: serde_json::Value = serde_json::from_str(&output).expect("invalid JSON");
+            assert_eq!(report["pid"], child.as_raw());
+            let threads = report["threads"].as_array().unwrap();
+            assert_eq!(threads.len(), 2);
+            assert!(threads.iter().all(|thread| thread["task"].is_null()));
+            // Only the name of the main thread is in the core.
+            let main = threads.iter().find(|thread| thread["lwp"] == child.as_raw()).expect("main thread missing");
+            assert!(main["name"].is_string());
+            assert!(threads.iter().filter(|thread| thread["lwp"] != child.as_raw()).all(|thread| thread["name"].is_null()));
+            assert!(threads.iter().all(|thread| !thread["backtrace"].as_array().expect("backtrace missing").is_empty()));
+            std::fs::remove_dir_all(dir).unwrap();
+        },
+    }
+}